@@ -0,0 +1,231 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::config::Config;
+use crate::state::{KeyboardBacklightState, KeyboardStateManager};
+
+const IIO_DEVICES_PATH: &str = "/sys/bus/iio/devices";
+
+/// Backlight level used while the ambient light is below `below_lux`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AutoBacklightThreshold {
+    pub below_lux: f64,
+    pub level: KeyboardBacklightState,
+}
+
+/// Find the IIO device of the ambient light sensor
+async fn find_light_sensor() -> Option<PathBuf> {
+    let mut entries = match fs::read_dir(IIO_DEVICES_PATH).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read {}: {}", IIO_DEVICES_PATH, e);
+            return None;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if fs::try_exists(path.join("in_illuminance_raw"))
+            .await
+            .unwrap_or(false)
+        {
+            return Some(path);
+        }
+    }
+    None
+}
+
+async fn read_f64(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).await.ok()?.trim().parse().ok()
+}
+
+/// Illuminance in lux, the scale and offset are optional
+async fn read_illuminance(sensor: &Path) -> Option<f64> {
+    let raw = read_f64(&sensor.join("in_illuminance_raw")).await?;
+    let scale = read_f64(&sensor.join("in_illuminance_scale"))
+        .await
+        .unwrap_or(1.0);
+    let offset = read_f64(&sensor.join("in_illuminance_offset"))
+        .await
+        .unwrap_or(0.0);
+    Some((raw + offset) * scale)
+}
+
+/// Checked when the config is loaded, the thresholds are searched in order
+pub fn validate_auto_backlight_thresholds(
+    thresholds: &[AutoBacklightThreshold],
+) -> Result<(), String> {
+    for pair in thresholds.windows(2) {
+        if pair[0].below_lux >= pair[1].below_lux {
+            return Err(format!(
+                "auto_backlight_thresholds must be in ascending order of below_lux, {} is followed by {}",
+                pair[0].below_lux, pair[1].below_lux
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The level of the first threshold the illuminance is below, off if it is above all thresholds
+fn level_for(thresholds: &[AutoBacklightThreshold], lux: f64) -> KeyboardBacklightState {
+    thresholds
+        .iter()
+        .find(|threshold| lux < threshold.below_lux)
+        .map(|threshold| threshold.level)
+        .unwrap_or(KeyboardBacklightState::Off)
+}
+
+/// How far the illuminance is outside the ranges that map to `level`, 0 if it is inside one
+fn distance_to_level(
+    thresholds: &[AutoBacklightThreshold],
+    level: KeyboardBacklightState,
+    lux: f64,
+) -> f64 {
+    let lower_bounds = std::iter::once(f64::NEG_INFINITY)
+        .chain(thresholds.iter().map(|threshold| threshold.below_lux));
+    let upper_bounds = thresholds
+        .iter()
+        .map(|threshold| (threshold.below_lux, threshold.level))
+        .chain(std::iter::once((
+            f64::INFINITY,
+            KeyboardBacklightState::Off,
+        )));
+    lower_bounds
+        .zip(upper_bounds)
+        .filter(|(_, (_, range_level))| *range_level == level)
+        .map(|(lower, (upper, _))| {
+            if lux < lower {
+                lower - lux
+            } else if lux >= upper {
+                lux - upper
+            } else {
+                0.0
+            }
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// The level to use for the illuminance given the current level.
+/// The current level is only left once the illuminance is `hysteresis` lux past the boundary of its range,
+/// so the backlight doesn't flicker between two levels when the illuminance is close to a threshold.
+fn next_level(
+    thresholds: &[AutoBacklightThreshold],
+    current_level: Option<KeyboardBacklightState>,
+    lux: f64,
+    hysteresis: f64,
+) -> KeyboardBacklightState {
+    match current_level {
+        Some(current_level) if distance_to_level(thresholds, current_level, lux) < hysteresis => {
+            current_level
+        }
+        _ => level_for(thresholds, lux),
+    }
+}
+
+/// Task to pick the backlight level from the ambient light sensor
+pub fn start_ambient_light_task(config: &Config, state_manager: KeyboardStateManager) {
+    if !config.auto_backlight {
+        return;
+    }
+    let thresholds = config.auto_backlight_thresholds.clone();
+    let hysteresis = config.auto_backlight_hysteresis_lux;
+
+    tokio::spawn(async move {
+        let Some(sensor) = find_light_sensor().await else {
+            warn!("No ambient light sensor found, auto backlight disabled");
+            return;
+        };
+        info!("Using ambient light sensor {}", sensor.display());
+
+        let mut current_level = None;
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let Some(lux) = read_illuminance(&sensor).await else {
+                continue;
+            };
+
+            let level = next_level(&thresholds, current_level, lux, hysteresis);
+            if current_level != Some(level) {
+                debug!("Ambient light {} lux, backlight {}", lux, level.name());
+                state_manager.set_auto_keyboard_backlight(level);
+                current_level = Some(level);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyboardBacklightState::{High, Low, Medium, Off};
+
+    fn thresholds() -> Vec<AutoBacklightThreshold> {
+        [(5.0, High), (20.0, Medium), (80.0, Low)]
+            .into_iter()
+            .map(|(below_lux, level)| AutoBacklightThreshold { below_lux, level })
+            .collect()
+    }
+
+    #[test]
+    fn level_for_picks_the_first_threshold_above() {
+        let thresholds = thresholds();
+        assert_eq!(level_for(&thresholds, 0.0), High);
+        assert_eq!(level_for(&thresholds, 4.9), High);
+        assert_eq!(level_for(&thresholds, 5.0), Medium);
+        assert_eq!(level_for(&thresholds, 79.9), Low);
+        assert_eq!(level_for(&thresholds, 80.0), Off);
+        assert_eq!(level_for(&[], 0.0), Off);
+    }
+
+    #[test]
+    fn first_reading_is_applied_directly() {
+        assert_eq!(next_level(&thresholds(), None, 20.5, 3.0), Low);
+    }
+
+    #[test]
+    fn level_is_kept_within_the_hysteresis() {
+        let thresholds = thresholds();
+        assert_eq!(next_level(&thresholds, Some(Medium), 22.9, 3.0), Medium);
+        assert_eq!(next_level(&thresholds, Some(Low), 17.1, 3.0), Low);
+    }
+
+    #[test]
+    fn level_is_left_past_the_hysteresis() {
+        let thresholds = thresholds();
+        assert_eq!(next_level(&thresholds, Some(Medium), 23.0, 3.0), Low);
+        assert_eq!(next_level(&thresholds, Some(Low), 17.0, 3.0), Medium);
+        // jumps straight to the level of the illuminance, not the neighbouring one
+        assert_eq!(next_level(&thresholds, Some(High), 100.0, 3.0), Off);
+    }
+
+    #[test]
+    fn narrow_range_is_reachable() {
+        // the Medium range is narrower than twice the hysteresis
+        let thresholds: Vec<_> = [(5.0, High), (8.0, Medium)]
+            .into_iter()
+            .map(|(below_lux, level)| AutoBacklightThreshold { below_lux, level })
+            .collect();
+        assert_eq!(next_level(&thresholds, Some(High), 6.5, 1.0), Medium);
+        assert_eq!(next_level(&thresholds, Some(Off), 6.5, 1.0), Medium);
+    }
+
+    #[test]
+    fn no_hysteresis_follows_the_thresholds() {
+        let thresholds = thresholds();
+        assert_eq!(next_level(&thresholds, Some(High), 5.0, 0.0), Medium);
+        assert_eq!(next_level(&thresholds, Some(Medium), 4.9, 0.0), High);
+    }
+
+    #[test]
+    fn thresholds_must_be_ascending() {
+        assert!(validate_auto_backlight_thresholds(&thresholds()).is_ok());
+        let mut thresholds = thresholds();
+        thresholds.swap(0, 1);
+        assert!(validate_auto_backlight_thresholds(&thresholds).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use log::{info, warn};
+use tokio::fs;
+use tokio::sync::broadcast;
+
+use crate::config::Config;
+use crate::events::Event;
+use crate::state::KeyboardStateManager;
+
+pub const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Charge limit of the "full charge" preset
+pub const FULL_CHARGE_LIMIT: u8 = 100;
+
+/// Find the charge_control_end_threshold files of all batteries
+async fn find_threshold_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let mut entries = match fs::read_dir(POWER_SUPPLY_PATH).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read {}: {}", POWER_SUPPLY_PATH, e);
+            return paths;
+        }
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with("BAT") {
+            let path = entry.path().join("charge_control_end_threshold");
+            if fs::try_exists(&path).await.unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+async fn read_current() -> Option<u8> {
+    let path = find_threshold_paths().await.into_iter().next()?;
+    fs::read_to_string(path).await.ok()?.trim().parse().ok()
+}
+
+async fn apply_charge_limit(limit: u8) {
+    for path in find_threshold_paths().await {
+        if let Err(e) = fs::write(&path, limit.to_string()).await {
+            warn!(
+                "Failed to set battery charge limit on {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Set the battery charge limit in percent
+pub fn set_battery_charge_limit(state_manager: &KeyboardStateManager, limit: u8) {
+    if limit == 0 || limit > FULL_CHARGE_LIMIT {
+        warn!("Invalid battery charge limit: {}", limit);
+        return;
+    }
+    state_manager.set_battery_charge_limit(limit);
+}
+
+/// Switch between the "max lifespan" and "full charge" presets
+pub async fn toggle_battery_charge_limit(config: &Config, state_manager: &KeyboardStateManager) {
+    let current = match state_manager.get_battery_charge_limit() {
+        Some(limit) => Some(limit),
+        None => read_current().await,
+    };
+
+    if current.unwrap_or(FULL_CHARGE_LIMIT) < FULL_CHARGE_LIMIT {
+        set_battery_charge_limit(state_manager, FULL_CHARGE_LIMIT);
+    } else {
+        set_battery_charge_limit(state_manager, config.battery_lifespan_charge_limit);
+    }
+}
+
+/// Battery consumer - writes the selected charge limit to all batteries
+pub async fn start_battery_task(
+    config: &Config,
+    state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
+) {
+    if find_threshold_paths().await.is_empty() {
+        info!("Battery charge limit is not supported on this device");
+        return;
+    }
+
+    if let Some(limit) = config.battery_charge_limit {
+        set_battery_charge_limit(&state_manager, limit);
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event::BatteryChargeLimit(limit)) => {
+                    info!("Setting battery charge limit to {}%", limit);
+                    apply_charge_limit(limit).await;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
+            }
+        }
+    });
+}
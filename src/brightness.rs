@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum BrightnessCurve {
+    /// Each step changes the raw brightness value by the same amount
+    Linear,
+    /// Each step changes the perceived brightness by the same amount,
+    /// so steps are finer at the dark end and coarser at the bright end
+    Logarithmic,
+}
+
+async fn read_value(path: &Path) -> Option<u32> {
+    match fs::read_to_string(path).await {
+        Ok(contents) => contents.trim().parse().ok(),
+        Err(e) => {
+            warn!("Failed to read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Compute the brightness after moving `step_percent` percent along `curve`.
+/// The result is clamped to `1..=max` so the panel never turns fully dark,
+/// and always differs from `current` unless it is already at the limit.
+fn next_brightness(current: u32, max: u32, step_percent: i32, curve: BrightnessCurve) -> u32 {
+    let step = step_percent as f64 / 100.0;
+    let target = match curve {
+        BrightnessCurve::Linear => current as f64 + step * max as f64,
+        BrightnessCurve::Logarithmic => {
+            // position on a log scale, 0.0 is fully dark and 1.0 is max brightness
+            let scale = (max as f64 + 1.0).ln();
+            let position = (current as f64 + 1.0).ln() / scale;
+            ((position + step).clamp(0.0, 1.0) * scale).exp() - 1.0
+        }
+    };
+
+    let mut target = target.round().clamp(1.0, max as f64) as u32;
+    if target == current && step_percent != 0 {
+        target = if step_percent > 0 {
+            current.saturating_add(1).min(max)
+        } else {
+            current.saturating_sub(1).max(1)
+        };
+    }
+    target
+}
+
+/// Step the brightness of the backlight at `brightness_path` up (positive `step_percent`) or down.
+/// `max_brightness` is read from the same directory.
+pub async fn step_brightness(brightness_path: &str, step_percent: i32, curve: BrightnessCurve) {
+    let brightness_path = Path::new(brightness_path);
+    let max_brightness_path = brightness_path.with_file_name("max_brightness");
+
+    let (Some(current), Some(max)) = (
+        read_value(brightness_path).await,
+        read_value(&max_brightness_path).await,
+    ) else {
+        return;
+    };
+    if max == 0 {
+        return;
+    }
+
+    let target = next_brightness(current.min(max), max, step_percent, curve);
+    info!(
+        "Setting brightness: {} -> {} (max {})",
+        current, target, max
+    );
+    if let Err(e) = fs::write(brightness_path, target.to_string()).await {
+        warn!("Failed to set brightness: {}", e);
+    }
+}
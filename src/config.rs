@@ -1,12 +1,45 @@
 use log::{info, warn};
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, path::PathBuf};
 use tokio::fs;
-use tokio::sync::Mutex;
 
 use evdev_rs::enums::EV_KEY;
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
-use crate::state::KeyboardStateManager;
+use crate::ambient_light::{AutoBacklightThreshold, validate_auto_backlight_thresholds};
+use crate::battery::{FULL_CHARGE_LIMIT, toggle_battery_charge_limit};
+use crate::brightness::{BrightnessCurve, step_brightness};
+use crate::dbus_call::{DBusArg, DBusBus, dbus_call, validate_dbus_call};
+use crate::function_key::{FunctionKey, FunctionKeyBinding, KeyRateLimiter};
+use crate::http::send_http_request;
+use crate::launcher::launch_desktop_entry;
+use crate::layer::{LayerBinding, LayerManager};
+use crate::modifiers::ModifierState;
+use crate::mpris::{MprisAction, control_media_player};
+use crate::mute_state::AudioController;
+use crate::platform_profile::cycle_platform_profile;
+use crate::power_source::PowerSource;
+use crate::schedule::BacklightScheduleEntry;
+use crate::state::{KeyboardBacklightState, KeyboardStateManager};
+use crate::toggle::persist_toggle;
+use crate::virtual_keyboard::{
+    KeyboardSource, MouseButton, Panel, ScrollDirection, VirtualKeyboard,
+};
+
+/// Handles needed to execute key functions.
+/// Clone this to share across multiple components.
+#[derive(Clone)]
+pub struct KeyFunctionContext {
+    pub source: KeyboardSource,
+    pub virtual_keyboard: VirtualKeyboard,
+    pub state_manager: KeyboardStateManager,
+    pub audio_controller: AudioController,
+    /// None when no layer key is configured
+    pub layer: Option<LayerManager>,
+    pub modifiers: ModifierState,
+    pub rate_limiter: KeyRateLimiter,
+    pub config: Config,
+}
 
 // All the enum carries a value so the serialized toml looks better
 #[derive(Serialize, Deserialize, Clone)]
@@ -15,37 +48,253 @@ pub enum KeyFunction {
     ToggleSecondaryDisplay(bool),
     KeyBind(Vec<EV_KEY>),
     Command(String),
+    ToggleMicMute(bool),
+    CycleAudioOutput {
+        /// Sink names to cycle through, all sinks are used if empty
+        sinks: Vec<String>,
+        /// Also move all playing streams to the new sink
+        move_streams: bool,
+    },
+    Brightness {
+        /// Percent to step the primary display brightness by, negative values step down
+        step_percent: i32,
+        curve: BrightnessCurve,
+    },
+    CyclePlatformProfile(bool),
+    ToggleBatteryChargeLimit(bool),
+    Toggle {
+        /// Name used to query and reset the toggle through the control pipe
+        name: String,
+        /// Executed when the toggle is switched on
+        on: Box<KeyFunction>,
+        /// Executed when the toggle is switched off
+        off: Box<KeyFunction>,
+        /// Remember the toggle state across daemon restarts
+        persist: bool,
+    },
+    Mpris {
+        action: MprisAction,
+        /// Player names to prefer in order, e.g. "spotify", the most recently active player is used if none is running
+        players: Vec<String>,
+    },
+    DBusCall {
+        bus: DBusBus,
+        destination: String,
+        path: String,
+        interface: String,
+        method: String,
+        args: Vec<DBusArg>,
+    },
+    Http {
+        method: String,
+        url: String,
+        headers: BTreeMap<String, String>,
+        /// Request body, placeholders like `{backlight}` are replaced with the daemon state
+        body: String,
+        /// 0 disables the timeout
+        timeout_seconds: u64,
+        /// Number of times a failed request is retried
+        retries: u32,
+    },
+    Launch {
+        /// Desktop entry id, e.g. "org.gnome.Nautilus.desktop"
+        desktop_id: String,
+    },
+    MouseClick(MouseButton),
+    Scroll {
+        direction: ScrollDirection,
+        /// Number of wheel detents to scroll
+        amount: i32,
+    },
+    MoveAbsolute {
+        panel: Panel,
+        /// Horizontal position in percent of the panel width
+        x_percent: f64,
+        /// Vertical position in percent of the panel height
+        y_percent: f64,
+    },
     NoOp(bool),
 }
 
 impl KeyFunction {
-    /// Execute a key function - handles KeyBind, Command, KeyboardBacklight, and ToggleSecondaryDisplay
-    pub async fn execute(
-        &self,
-        virtual_keyboard: &Arc<Mutex<crate::virtual_keyboard::VirtualKeyboard>>,
-        state_manager: &KeyboardStateManager,
-    ) {
+    /// Keys that may be emitted by this key function, including the ones in Toggle branches
+    pub fn bound_keys(&self) -> Vec<EV_KEY> {
         match self {
-            KeyFunction::KeyBind(items) => {
-                virtual_keyboard
-                    .lock()
-                    .await
-                    .release_prev_and_press_keys(items);
-            }
-            KeyFunction::Command(command) => {
-                crate::execute_command(command);
-            }
-            KeyFunction::KeyboardBacklight(true) => {
-                state_manager.toggle_keyboard_backlight();
+            KeyFunction::KeyBind(keys) => keys.clone(),
+            KeyFunction::Toggle { on, off, .. } => {
+                let mut keys = on.bound_keys();
+                keys.extend(off.bound_keys());
+                keys
             }
-            KeyFunction::ToggleSecondaryDisplay(true) => {
-                state_manager.toggle_secondary_display();
-            }
-            _ => {
-                // do nothing
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks that can't be expressed in the types, including the ones in Toggle branches
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            KeyFunction::DBusCall {
+                destination,
+                path,
+                interface,
+                method,
+                args,
+                ..
+            } => validate_dbus_call(destination, path, interface, method, args),
+            KeyFunction::Toggle { on, off, .. } => {
+                on.validate()?;
+                off.validate()
             }
+            _ => Ok(()),
         }
     }
+
+    /// Execute a key function
+    pub fn execute<'a>(&'a self, ctx: &'a KeyFunctionContext) -> BoxFuture<'a, ()> {
+        // boxed because Toggle executes its branches recursively
+        Box::pin(async move {
+            match self {
+                KeyFunction::KeyBind(items) => {
+                    if let Err(e) = ctx.virtual_keyboard.release_prev_and_press_keys(items) {
+                        warn!("Failed to press {:?}: {}", items, e);
+                    }
+                }
+                KeyFunction::Command(command) => {
+                    crate::execute_command(command);
+                }
+                KeyFunction::KeyboardBacklight(true) => {
+                    ctx.state_manager.toggle_keyboard_backlight();
+                }
+                KeyFunction::ToggleSecondaryDisplay(true) => {
+                    ctx.state_manager.toggle_secondary_display();
+                }
+                KeyFunction::ToggleMicMute(true) => {
+                    ctx.audio_controller.toggle_default_source_mute().await;
+                }
+                KeyFunction::CycleAudioOutput {
+                    sinks,
+                    move_streams,
+                } => {
+                    ctx.audio_controller
+                        .cycle_default_sink(sinks.clone(), *move_streams)
+                        .await;
+                }
+                KeyFunction::Brightness {
+                    step_percent,
+                    curve,
+                } => {
+                    step_brightness(&ctx.config.primary_backlight_path, *step_percent, *curve)
+                        .await;
+                }
+                KeyFunction::CyclePlatformProfile(true) => {
+                    cycle_platform_profile(&ctx.state_manager).await;
+                }
+                KeyFunction::ToggleBatteryChargeLimit(true) => {
+                    toggle_battery_charge_limit(&ctx.config, &ctx.state_manager).await;
+                }
+                KeyFunction::Toggle {
+                    name,
+                    on,
+                    off,
+                    persist,
+                } => {
+                    let is_on = ctx.state_manager.flip_toggle(name);
+                    if *persist {
+                        persist_toggle(name, Some(is_on)).await;
+                    }
+                    if is_on {
+                        on.execute(ctx).await;
+                    } else {
+                        off.execute(ctx).await;
+                    }
+                }
+                KeyFunction::Mpris { action, players } => {
+                    control_media_player(*action, players, &ctx.state_manager).await;
+                }
+                KeyFunction::DBusCall {
+                    bus,
+                    destination,
+                    path,
+                    interface,
+                    method,
+                    args,
+                } => {
+                    dbus_call(*bus, destination, path, interface, method, args).await;
+                }
+                KeyFunction::Http {
+                    method,
+                    url,
+                    headers,
+                    body,
+                    timeout_seconds,
+                    retries,
+                } => {
+                    send_http_request(
+                        method,
+                        url,
+                        headers,
+                        body,
+                        *timeout_seconds,
+                        *retries,
+                        &ctx.state_manager,
+                    );
+                }
+                KeyFunction::Launch { desktop_id } => {
+                    launch_desktop_entry(desktop_id).await;
+                }
+                KeyFunction::MouseClick(button) => {
+                    if let Err(e) = ctx.virtual_keyboard.click(*button) {
+                        warn!("Failed to click {:?}: {}", button, e);
+                    }
+                }
+                KeyFunction::Scroll { direction, amount } => {
+                    if let Err(e) = ctx.virtual_keyboard.scroll(*direction, *amount) {
+                        warn!("Failed to scroll {:?}: {}", direction, e);
+                    }
+                }
+                KeyFunction::MoveAbsolute {
+                    panel,
+                    x_percent,
+                    y_percent,
+                } => {
+                    let is_secondary_display_enabled =
+                        ctx.state_manager.is_secondary_display_enabled();
+                    if let Err(e) = ctx.virtual_keyboard.move_pointer_to(
+                        *panel,
+                        *x_percent,
+                        *y_percent,
+                        is_secondary_display_enabled,
+                    ) {
+                        warn!("Failed to move pointer: {}", e);
+                    }
+                }
+                _ => {
+                    // do nothing
+                }
+            }
+        })
+    }
+}
+
+/// Idle timeouts used instead of the defaults while the power source and connection mode match.
+/// A missing condition matches anything.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IdleTimeoutRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_source: Option<PowerSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<KeyboardSource>,
+    #[serde(default)]
+    pub idle_dim_timeout_seconds: u64,
+    pub idle_timeout_seconds: u64,
+}
+
+impl IdleTimeoutRule {
+    fn matches(&self, power_source: PowerSource, connection: KeyboardSource) -> bool {
+        self.power_source
+            .is_none_or(|source| source == power_source)
+            && self.connection.is_none_or(|mode| mode == connection)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -53,20 +302,65 @@ pub struct Config {
     usb_vendor_id: String,
     usb_product_id: String,
     pub fn_lock: bool,
-    pub keyboard_backlight_key: KeyFunction,
-    pub brightness_down_key: KeyFunction,
-    pub brightness_up_key: KeyFunction,
-    pub swap_up_down_display_key: KeyFunction,
-    pub microphone_mute_key: KeyFunction,
-    pub emoji_picker_key: KeyFunction,
-    pub myasus_key: KeyFunction,
-    pub toggle_secondary_display_key: KeyFunction,
+    /// Backlight level used until a level is selected while the keyboard is attached over USB
+    #[serde(default = "default_keyboard_backlight")]
+    pub usb_keyboard_backlight: KeyboardBacklightState,
+    /// Backlight level used until a level is selected while the keyboard is connected over Bluetooth.
+    /// Not sent to the keyboard yet, setting the backlight over Bluetooth isn't supported.
+    #[serde(default = "default_keyboard_backlight")]
+    pub bluetooth_keyboard_backlight: KeyboardBacklightState,
+    /// Pick the backlight level from the ambient light sensor, a manual change pauses this until the next idle or resume
+    #[serde(default)]
+    pub auto_backlight: bool,
+    /// In ascending order, the first threshold the ambient light is below is used, the backlight is off above all thresholds
+    #[serde(default = "default_auto_backlight_thresholds")]
+    pub auto_backlight_thresholds: Vec<AutoBacklightThreshold>,
+    /// The current level is only left once the ambient light is this far past its threshold
+    #[serde(default = "default_auto_backlight_hysteresis_lux")]
+    pub auto_backlight_hysteresis_lux: f64,
+    /// Times of day at which the backlight level changes, a manual change lasts until the next one
+    #[serde(default)]
+    pub backlight_schedule: Vec<BacklightScheduleEntry>,
+    pub keyboard_backlight_key: FunctionKeyBinding,
+    pub brightness_down_key: FunctionKeyBinding,
+    pub brightness_up_key: FunctionKeyBinding,
+    pub swap_up_down_display_key: FunctionKeyBinding,
+    pub microphone_mute_key: FunctionKeyBinding,
+    pub emoji_picker_key: FunctionKeyBinding,
+    pub myasus_key: FunctionKeyBinding,
+    pub toggle_secondary_display_key: FunctionKeyBinding,
+    /// Function key that activates the layer while held, its key function is not executed
+    #[serde(default)]
+    pub layer_key: Option<FunctionKey>,
+    /// Alternate mappings of regular keys while the layer key is held
+    #[serde(default)]
+    pub layer_bindings: Vec<LayerBinding>,
     pub secondary_display_status_path: String,
     pub primary_backlight_path: String,
     pub secondary_backlight_path: String,
     pub pipe_path: String,
-    /// Idle timeout in seconds. Set to 0 to disable idle detection.
+    #[serde(default = "default_socket_path")]
+    pub socket_path: String,
+    /// Inactivity in seconds before the backlight is dimmed to low. Set to 0 to skip dimming.
+    #[serde(default)]
+    pub idle_dim_timeout_seconds: u64,
+    /// Idle timeout in seconds, counted from dimming if enabled. Set to 0 to never turn the backlight off.
     pub idle_timeout_seconds: u64,
+    /// Idle timeouts for specific power sources and connection modes, the first matching rule is used
+    #[serde(default)]
+    pub idle_timeout_rules: Vec<IdleTimeoutRule>,
+    /// Presses of the same function key within this time are treated as one. Set to 0 to disable.
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Minimum time between two executions of the same function key. Set to 0 to disable.
+    #[serde(default = "default_min_action_interval_ms")]
+    pub min_action_interval_ms: u64,
+    /// Battery charge limit in percent applied on startup. The firmware setting is left untouched if missing.
+    #[serde(default)]
+    pub battery_charge_limit: Option<u8>,
+    /// Battery charge limit in percent of the "max lifespan" preset
+    #[serde(default = "default_battery_lifespan_charge_limit")]
+    pub battery_lifespan_charge_limit: u8,
 }
 
 impl Config {
@@ -77,6 +371,32 @@ impl Config {
     pub fn product_id(&self) -> u16 {
         u16::from_str_radix(&self.usb_product_id, 16).unwrap()
     }
+
+    /// Dim and idle timeouts in seconds of the first matching rule, or the defaults if none matches
+    pub fn idle_timeouts(
+        &self,
+        power_source: PowerSource,
+        connection: KeyboardSource,
+    ) -> (u64, u64) {
+        self.idle_timeout_rules
+            .iter()
+            .find(|rule| rule.matches(power_source, connection))
+            .map(|rule| (rule.idle_dim_timeout_seconds, rule.idle_timeout_seconds))
+            .unwrap_or((self.idle_dim_timeout_seconds, self.idle_timeout_seconds))
+    }
+
+    pub fn function_key_binding(&self, key: FunctionKey) -> &FunctionKeyBinding {
+        match key {
+            FunctionKey::KeyboardBacklight => &self.keyboard_backlight_key,
+            FunctionKey::BrightnessDown => &self.brightness_down_key,
+            FunctionKey::BrightnessUp => &self.brightness_up_key,
+            FunctionKey::SwapUpDownDisplay => &self.swap_up_down_display_key,
+            FunctionKey::MicrophoneMute => &self.microphone_mute_key,
+            FunctionKey::EmojiPicker => &self.emoji_picker_key,
+            FunctionKey::MyAsus => &self.myasus_key,
+            FunctionKey::ToggleSecondaryDisplay => &self.toggle_secondary_display_key,
+        }
+    }
 }
 
 fn get_usb_product_id() -> String {
@@ -99,31 +419,91 @@ fn get_usb_product_id() -> String {
     }
 }
 
+// Defaults of the settings added after the first release, so older config files keep loading
+
+fn default_keyboard_backlight() -> KeyboardBacklightState {
+    KeyboardBacklightState::Low
+}
+
+fn default_auto_backlight_thresholds() -> Vec<AutoBacklightThreshold> {
+    vec![
+        AutoBacklightThreshold {
+            below_lux: 5.0,
+            level: KeyboardBacklightState::High,
+        },
+        AutoBacklightThreshold {
+            below_lux: 20.0,
+            level: KeyboardBacklightState::Medium,
+        },
+        AutoBacklightThreshold {
+            below_lux: 80.0,
+            level: KeyboardBacklightState::Low,
+        },
+    ]
+}
+
+fn default_auto_backlight_hysteresis_lux() -> f64 {
+    3.0
+}
+
+fn default_socket_path() -> String {
+    DEFAULT_SOCKET_PATH.to_string()
+}
+
+fn default_debounce_ms() -> u64 {
+    50
+}
+
+fn default_min_action_interval_ms() -> u64 {
+    100
+}
+
+fn default_battery_lifespan_charge_limit() -> u8 {
+    80
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             usb_vendor_id: "0b05".to_string(),
             usb_product_id: get_usb_product_id(),
             fn_lock: true,
-            keyboard_backlight_key: KeyFunction::KeyboardBacklight(true),
-            brightness_down_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_BRIGHTNESSDOWN]),
-            brightness_up_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_BRIGHTNESSUP]),
-            swap_up_down_display_key: KeyFunction::NoOp(true),
-            microphone_mute_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_MICMUTE]),
-            emoji_picker_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_DOT]),
-            myasus_key: KeyFunction::NoOp(true),
-            toggle_secondary_display_key: KeyFunction::ToggleSecondaryDisplay(true),
+            usb_keyboard_backlight: default_keyboard_backlight(),
+            bluetooth_keyboard_backlight: default_keyboard_backlight(),
+            auto_backlight: false,
+            auto_backlight_thresholds: default_auto_backlight_thresholds(),
+            auto_backlight_hysteresis_lux: default_auto_backlight_hysteresis_lux(),
+            backlight_schedule: Vec::new(),
+            keyboard_backlight_key: KeyFunction::KeyboardBacklight(true).into(),
+            brightness_down_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_BRIGHTNESSDOWN]).into(),
+            brightness_up_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_BRIGHTNESSUP]).into(),
+            swap_up_down_display_key: KeyFunction::NoOp(true).into(),
+            microphone_mute_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_MICMUTE]).into(),
+            emoji_picker_key: KeyFunction::KeyBind(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_DOT])
+                .into(),
+            myasus_key: KeyFunction::NoOp(true).into(),
+            toggle_secondary_display_key: KeyFunction::ToggleSecondaryDisplay(true).into(),
+            layer_key: None,
+            layer_bindings: Vec::new(),
             secondary_display_status_path: "/sys/class/drm/card1-eDP-2/status".to_string(),
             primary_backlight_path: "/sys/class/backlight/intel_backlight/brightness".to_string(),
             secondary_backlight_path: "/sys/class/backlight/card1-eDP-2-backlight/brightness"
                 .to_string(),
             pipe_path: "/tmp/zenbook-duo-daemon.pipe".to_string(),
+            socket_path: default_socket_path(),
+            idle_dim_timeout_seconds: 0,
             idle_timeout_seconds: 300, // 5 minutes
+            idle_timeout_rules: Vec::new(),
+            debounce_ms: default_debounce_ms(),
+            min_action_interval_ms: default_min_action_interval_ms(),
+            battery_charge_limit: None,
+            battery_lifespan_charge_limit: default_battery_lifespan_charge_limit(),
         }
     }
 }
 
 pub const DEFAULT_CONFIG_PATH: &str = "/etc/zenbook-duo-daemon/config.toml";
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/zenbook-duo-daemon.sock";
 
 impl Config {
     pub async fn write_default_config(config_path: &PathBuf) {
@@ -138,10 +518,55 @@ impl Config {
 # Command = \"echo 'Hello, world!'\"          # Runs a custom command as root when the physical key is pressed
 # KeyboardBacklight = true                  # Toggles the keyboard backlight
 # ToggleSecondaryDisplay = true             # Toggles the secondary display
+# ToggleMicMute = true                      # Mutes/unmutes the default microphone through PulseAudio/PipeWire
+# CycleAudioOutput = { sinks = [], move_streams = true }  # Switches to the next audio output, sink names can be found with `pactl list short sinks`
+# Brightness = { step_percent = 5, curve = \"Logarithmic\" }  # Changes the display brightness without a desktop, use a negative step to decrease, curve can be \"Linear\" or \"Logarithmic\"
+# CyclePlatformProfile = true               # Cycles the platform profile (e.g. quiet, balanced, performance)
+# ToggleBatteryChargeLimit = true           # Switches the battery charge limit between the max lifespan preset and full charge
+# Toggle = { name = \"recording\", on = { Command = \"...\" }, off = { Command = \"...\" }, persist = false }  # Runs `on` on the first press and `off` on the next press
+# Mpris = { action = \"PlayPause\", players = [] }  # Controls a media player, action can be PlayPause, Play, Pause, Stop, Next, Previous or Raise
+# DBusCall = { bus = \"Session\", destination = \"org.gnome.Shell\", path = \"/org/gnome/Shell\", interface = \"org.gnome.Shell\", method = \"ShowApplications\", args = [] }  # Calls a D-Bus method, bus can be System or Session, args are typed e.g. [{ String = \"foo\" }, { UInt32 = 1 }], arrays and dicts are not supported
+# Http = { method = \"POST\", url = \"http://localhost:8123/api/webhook/duo\", headers = { \"Content-Type\" = \"application/json\" }, body = '{\"backlight\": \"{backlight}\"}', timeout_seconds = 5, retries = 2 }  # Sends an HTTP request (timeout_seconds = 0 disables the timeout), body placeholders: {backlight} {mic_mute_led} {secondary_display} {usb_attached} {platform_profile} {battery_charge_limit}
+# Launch = { desktop_id = \"org.gnome.Nautilus.desktop\" }  # Launches an application as the logged in user, desktop entries are in /usr/share/applications, ~/.local/share/applications, etc.
+# MouseClick = \"Middle\"                    # Clicks a mouse button, can be Left, Right or Middle
+# Scroll = { direction = \"Down\", amount = 3 }  # Scrolls the mouse wheel, direction can be Up, Down, Left or Right
+# MoveAbsolute = { panel = \"Secondary\", x_percent = 50.0, y_percent = 50.0 }  # Moves the pointer to a position on the Primary or Secondary display
 # NoOp = true                               # Does nothing when the physical key is pressed
 #
-# fn_lock = true             # To input F1-F12, you need to press Fn + F1-F12
-# idle_timeout_seconds = 300 # 5 minutes, set to 0 to disable idle detection
+# [keyboard_backlight_key.shift]            # Optional, used instead when the physical key is pressed while shift is held, ctrl and alt are also available
+# Command = \"echo 'Hello, shift!'\"
+#
+# fn_lock = true             # To input F1-F12, you need to press Fn + F1-F12, changes through the control pipe are remembered and take precedence
+# layer_key = \"MyAsus\"      # Holding this function key activates the layer, can be KeyboardBacklight, BrightnessDown, BrightnessUp, SwapUpDownDisplay, MicrophoneMute, EmojiPicker, MyAsus or ToggleSecondaryDisplay
+# usb_keyboard_backlight = \"Low\"       # Backlight level while attached over USB, can be Off, Low, Medium or High. Each connection mode remembers the level selected last
+# bluetooth_keyboard_backlight = \"Low\" # Backlight level while connected over Bluetooth, it is tracked but not sent to the keyboard yet, so it has no effect
+# auto_backlight = false     # Pick the backlight level from the ambient light sensor, changing the level manually pauses this until the next idle or resume
+# auto_backlight_thresholds = [{ below_lux = 5.0, level = \"High\" }, { below_lux = 20.0, level = \"Medium\" }, { below_lux = 80.0, level = \"Low\" }] # In ascending order, the first threshold the ambient light is below is used, off above all thresholds
+# auto_backlight_hysteresis_lux = 3.0 # The current level is only left once the ambient light is this far past its threshold
+# idle_dim_timeout_seconds = 0 # Dim the backlight to low after this many seconds of inactivity, set to 0 to skip dimming
+# idle_timeout_seconds = 300 # 5 minutes, the backlight turns off after this many seconds of inactivity (counted from dimming if enabled), set to 0 to never turn it off
+# debounce_ms = 50           # Presses of the same function key within 50ms are treated as one, set to 0 to disable
+# min_action_interval_ms = 100 # Minimum time between two executions of the same function key, set to 0 to disable
+# battery_charge_limit = 80  # Battery charge limit in percent applied on startup, leave it out to keep the firmware setting
+# battery_lifespan_charge_limit = 80 # Battery charge limit in percent of the max lifespan preset
+#
+# [[idle_timeout_rules]]     # Overrides the idle timeouts above, the first rule matching the power source (Ac or Battery) and connection mode (Usb or Bluetooth) is used
+# power_source = \"Battery\"  # Optional, matches any power source if missing
+# connection = \"Bluetooth\"  # Optional, matches any connection mode if missing
+# idle_dim_timeout_seconds = 15 # Optional, defaults to 0
+# idle_timeout_seconds = 30
+#
+# [[backlight_schedule]]     # At each local time of day the backlight is set to `level` until the next entry, takes precedence over auto_backlight. Changing the level manually lasts until the next entry
+# at = \"08:00\"
+# level = \"Off\"
+#
+# [[backlight_schedule]]
+# at = \"18:00\"
+# level = \"Low\"
+#
+# [[layer_bindings]]         # While the layer key is held, pressing `key` emits `keys` instead, unbound keys work as usual
+# key = \"KEY_H\"
+# keys = [\"KEY_LEFT\"]
         ".trim();
         let config_str = format!("{}\n\n\n{}", help, config_str);
 
@@ -157,7 +582,58 @@ impl Config {
         let config_str = fs::read_to_string(config_path)
             .await
             .map_err(|e| format!("Failed to read config file: {}", e))?;
-        toml::from_str(&config_str).map_err(|e| format!("Failed to parse config file: {}", e))
+        Self::parse(&config_str)
+    }
+
+    fn parse(config_str: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(config_str)
+            .map_err(|e| format!("Failed to parse config file: {}", e))?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid config file: {}", e))?;
+        Ok(config)
+    }
+
+    /// Checks that can't be expressed in the types, so mistakes are reported on startup instead of on use
+    fn validate(&self) -> Result<(), String> {
+        for function in self.key_functions() {
+            function.validate()?;
+        }
+        validate_auto_backlight_thresholds(&self.auto_backlight_thresholds)?;
+        if self.auto_backlight_hysteresis_lux < 0.0 {
+            return Err("auto_backlight_hysteresis_lux must not be negative".to_string());
+        }
+        if let Some(limit) = self.battery_charge_limit
+            && !(1..=FULL_CHARGE_LIMIT).contains(&limit)
+        {
+            return Err(format!(
+                "battery_charge_limit must be between 1 and {}",
+                FULL_CHARGE_LIMIT
+            ));
+        }
+        if !(1..=FULL_CHARGE_LIMIT).contains(&self.battery_lifespan_charge_limit) {
+            return Err(format!(
+                "battery_lifespan_charge_limit must be between 1 and {}",
+                FULL_CHARGE_LIMIT
+            ));
+        }
+        Ok(())
+    }
+
+    /// Key functions of all function key bindings, including the modifier bindings
+    pub fn key_functions(&self) -> impl Iterator<Item = &KeyFunction> {
+        [
+            &self.keyboard_backlight_key,
+            &self.brightness_down_key,
+            &self.brightness_up_key,
+            &self.swap_up_down_display_key,
+            &self.microphone_mute_key,
+            &self.emoji_picker_key,
+            &self.myasus_key,
+            &self.toggle_secondary_display_key,
+        ]
+        .into_iter()
+        .flat_map(FunctionKeyBinding::functions)
     }
 
     /// Read config file, creating default if it doesn't exist
@@ -166,6 +642,94 @@ impl Config {
             Self::write_default_config(config_path).await;
         }
         let config_str = fs::read_to_string(config_path).await.unwrap();
-        toml::from_str(&config_str).unwrap()
+        Self::parse(&config_str).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Written by the first release, before any of the newer settings existed
+    const FIRST_RELEASE_CONFIG: &str = r#"
+usb_vendor_id = "0b05"
+usb_product_id = "1b2c"
+fn_lock = true
+secondary_display_status_path = "/sys/class/drm/card1-eDP-2/status"
+primary_backlight_path = "/sys/class/backlight/intel_backlight/brightness"
+secondary_backlight_path = "/sys/class/backlight/card1-eDP-2-backlight/brightness"
+pipe_path = "/tmp/zenbook-duo-daemon.pipe"
+idle_timeout_seconds = 300
+
+[keyboard_backlight_key]
+KeyboardBacklight = true
+
+[brightness_down_key]
+KeyBind = ["KEY_BRIGHTNESSDOWN"]
+
+[brightness_up_key]
+KeyBind = ["KEY_BRIGHTNESSUP"]
+
+[swap_up_down_display_key]
+NoOp = true
+
+[microphone_mute_key]
+KeyBind = ["KEY_MICMUTE"]
+
+[emoji_picker_key]
+KeyBind = ["KEY_LEFTCTRL", "KEY_DOT"]
+
+[myasus_key]
+NoOp = true
+
+[toggle_secondary_display_key]
+ToggleSecondaryDisplay = true
+"#;
+
+    #[test]
+    fn first_release_config() {
+        let config = Config::parse(FIRST_RELEASE_CONFIG).unwrap();
+        assert_eq!(config.socket_path, DEFAULT_SOCKET_PATH);
+        assert_eq!(config.debounce_ms, 50);
+        assert_eq!(config.auto_backlight_thresholds.len(), 3);
+        assert_eq!(config.battery_charge_limit, None);
+        assert_eq!(config.battery_lifespan_charge_limit, 80);
+        assert!(config.layer_key.is_none());
+    }
+
+    #[test]
+    fn battery_charge_limits() {
+        let config = |limit, lifespan_limit| {
+            // top-level keys have to come before the tables
+            let toml = format!(
+                "battery_charge_limit = {}\nbattery_lifespan_charge_limit = {}\n{}",
+                limit, lifespan_limit, FIRST_RELEASE_CONFIG
+            );
+            Config::parse(&toml)
+        };
+        assert!(config(1, 100).is_ok());
+        assert!(config(0, 80).is_err());
+        assert!(config(101, 80).is_err());
+        assert!(config(80, 0).is_err());
+        assert!(config(80, 101).is_err());
+    }
+
+    #[test]
+    fn dbus_call_in_toggle_branch() {
+        let config = |method: &str| {
+            let toml = FIRST_RELEASE_CONFIG.replace(
+                "[myasus_key]\nNoOp = true",
+                &format!(
+                    "[myasus_key]\nToggle = {{ name = \"overview\", on = {{ NoOp = true }}, \
+                     off = {{ DBusCall = {{ bus = \"Session\", destination = \"org.gnome.Shell\", \
+                     path = \"/org/gnome/Shell\", interface = \"org.gnome.Shell\", method = \"{}\", args = [] }} }}, \
+                     persist = false }}",
+                    method
+                ),
+            );
+            Config::parse(&toml)
+        };
+        assert!(config("ShowApplications").is_ok());
+        assert!(config("Show Applications").is_err());
     }
 }
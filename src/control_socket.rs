@@ -0,0 +1,199 @@
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::battery::{FULL_CHARGE_LIMIT, set_battery_charge_limit, toggle_battery_charge_limit};
+use crate::config::Config;
+use crate::history::HistorySource;
+use crate::platform_profile::{cycle_platform_profile, set_platform_profile};
+use crate::state::{KeyboardStateManager, Status};
+
+/// Requests are cut off after this many bytes, the socket is accessible by all users
+const MAX_REQUEST_LENGTH: u64 = 4096;
+
+/// Clients that don't send a request within this time are disconnected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests that write to the firmware, only accepted from root
+const ROOT_REQUESTS: &[&str] = &[
+    "platform_profile_next",
+    "platform_profile_set",
+    "battery_charge_limit_toggle",
+    "battery_charge_limit_lifespan",
+    "battery_charge_limit_full",
+    "battery_charge_limit_set",
+];
+
+/// Response of the `status` request
+#[derive(Serialize)]
+struct StatusResponse {
+    version: &'static str,
+    #[serde(flatten)]
+    status: Status,
+}
+
+fn error_response(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| {
+        warn!("Failed to serialize control socket response: {}", e);
+        error_response(&format!("Failed to serialize response: {}", e))
+    })
+}
+
+fn ok_response() -> String {
+    serde_json::json!({ "ok": true }).to_string()
+}
+
+async fn handle_request(
+    request: &str,
+    is_root: bool,
+    config: &Config,
+    state_manager: &KeyboardStateManager,
+) -> String {
+    let (command, argument) = match request.split_once(' ') {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (request, ""),
+    };
+    if ROOT_REQUESTS.contains(&command) {
+        if !is_root {
+            warn!(
+                "Rejected control socket request from non-root user: {}",
+                request
+            );
+            return error_response(&format!("{} is only accepted from root", command));
+        }
+        state_manager.record(HistorySource::ControlSocket, request);
+    }
+
+    match (command, argument) {
+        ("status", "") => to_json(&StatusResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            status: state_manager.status(),
+        }),
+        ("history", "") => to_json(&state_manager.history()),
+        ("toggle", name) if !name.is_empty() => {
+            serde_json::json!({ "name": name, "enabled": state_manager.get_toggle(name) })
+                .to_string()
+        }
+        ("platform_profile_next", "") => {
+            cycle_platform_profile(state_manager).await;
+            ok_response()
+        }
+        ("platform_profile_set", profile) if !profile.is_empty() => {
+            set_platform_profile(state_manager, profile).await;
+            ok_response()
+        }
+        ("battery_charge_limit_toggle", "") => {
+            toggle_battery_charge_limit(config, state_manager).await;
+            ok_response()
+        }
+        ("battery_charge_limit_lifespan", "") => {
+            set_battery_charge_limit(state_manager, config.battery_lifespan_charge_limit);
+            ok_response()
+        }
+        ("battery_charge_limit_full", "") => {
+            set_battery_charge_limit(state_manager, FULL_CHARGE_LIMIT);
+            ok_response()
+        }
+        ("battery_charge_limit_set", limit) => match limit.parse() {
+            Ok(limit) => {
+                set_battery_charge_limit(state_manager, limit);
+                ok_response()
+            }
+            Err(_) => error_response(&format!("Invalid battery charge limit: {}", limit)),
+        },
+        _ => error_response(&format!("Unknown request: {}", request)),
+    }
+}
+
+/// Each connection sends one request line and receives one JSON response, then the connection is closed
+async fn handle_connection(
+    stream: UnixStream,
+    config: Config,
+    state_manager: KeyboardStateManager,
+) {
+    let is_root = stream.peer_cred().is_ok_and(|cred| cred.uid() == 0);
+    let (reader, mut writer) = stream.into_split();
+    let mut request = String::new();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_LENGTH));
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut request)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            warn!("Failed to read control socket request: {}", e);
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out waiting for a control socket request");
+            return;
+        }
+    }
+    let request = request.trim();
+    debug!("Received control socket request: {}", request);
+
+    let mut response = handle_request(request, is_root, &config, &state_manager).await;
+    response.push('\n');
+    if let Err(e) = writer.write_all(response.as_bytes()).await {
+        warn!("Failed to send control socket response: {}", e);
+    }
+}
+
+/// Unlike the control pipe, the control socket answers requests, e.g. `status`, `history` or `toggle <name>`.
+/// Requests that write to the firmware are checked against the peer credentials, see `ROOT_REQUESTS`.
+pub fn start_control_socket_task(config: &Config, state_manager: KeyboardStateManager) {
+    let path = PathBuf::from(&config.socket_path);
+    let config = config.clone();
+    tokio::spawn(async move {
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            fs::remove_file(&path).await.ok();
+            info!("Removed existing socket file");
+        }
+
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Failed to bind control socket {}: {}", path.display(), e);
+                return;
+            }
+        };
+        // accessible by all users, like the control pipe
+        if let Err(e) = fs::set_permissions(&path, Permissions::from_mode(0o666)).await {
+            warn!("Failed to set control socket permissions: {}", e);
+        }
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        config.clone(),
+                        state_manager.clone(),
+                    ));
+                }
+                Err(e) => {
+                    warn!("Failed to accept control socket connection: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Send `request` to the daemon's control socket and return the response
+pub async fn query(socket_path: &Path, request: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path).await?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
@@ -0,0 +1,136 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use zbus::{
+    Connection,
+    names::{BusName, InterfaceName, MemberName},
+    zvariant::{ObjectPath, StructureBuilder, Value},
+};
+
+use crate::user_session::connect_session_bus;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum DBusBus {
+    System,
+    /// Session bus of the logged in user
+    Session,
+}
+
+/// A typed D-Bus method argument, e.g. `{ String = "foo" }` or `{ UInt32 = 1 }`.
+/// Arrays and dicts are not supported.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum DBusArg {
+    Bool(bool),
+    Byte(u8),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+}
+
+impl DBusArg {
+    fn to_value(&self) -> zbus::Result<Value<'_>> {
+        Ok(match self {
+            Self::Bool(value) => Value::from(*value),
+            Self::Byte(value) => Value::from(*value),
+            Self::Int32(value) => Value::from(*value),
+            Self::UInt32(value) => Value::from(*value),
+            Self::Int64(value) => Value::from(*value),
+            Self::UInt64(value) => Value::from(*value),
+            Self::Double(value) => Value::from(*value),
+            Self::String(value) => Value::from(value.as_str()),
+            Self::ObjectPath(value) => Value::from(ObjectPath::try_from(value.as_str())?),
+        })
+    }
+}
+
+/// Check that the names are valid and all args can be converted, e.g. that object paths are valid
+pub fn validate_dbus_call(
+    destination: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+    args: &[DBusArg],
+) -> Result<(), String> {
+    BusName::try_from(destination)
+        .map_err(|e| format!("Invalid D-Bus destination {}: {}", destination, e))?;
+    ObjectPath::try_from(path).map_err(|e| format!("Invalid D-Bus path {}: {}", path, e))?;
+    InterfaceName::try_from(interface)
+        .map_err(|e| format!("Invalid D-Bus interface {}: {}", interface, e))?;
+    MemberName::try_from(method).map_err(|e| format!("Invalid D-Bus method {}: {}", method, e))?;
+    for arg in args {
+        arg.to_value()
+            .map_err(|e| format!("Invalid D-Bus argument {:?}: {}", arg, e))?;
+    }
+    Ok(())
+}
+
+async fn connect(bus: DBusBus) -> Option<Connection> {
+    match bus {
+        DBusBus::System => match Connection::system().await {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                warn!("Failed to connect to the system bus: {}", e);
+                None
+            }
+        },
+        DBusBus::Session => connect_session_bus().await,
+    }
+}
+
+async fn call(
+    connection: &Connection,
+    destination: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+    args: &[DBusArg],
+) -> zbus::Result<()> {
+    if args.is_empty() {
+        connection
+            .call_method(Some(destination), path, Some(interface), method, &())
+            .await?;
+    } else {
+        let mut body = StructureBuilder::new();
+        for arg in args {
+            body = body.append_field(arg.to_value()?);
+        }
+        connection
+            .call_method(
+                Some(destination),
+                path,
+                Some(interface),
+                method,
+                &body.build()?,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Call a D-Bus method and log the outcome
+pub async fn dbus_call(
+    bus: DBusBus,
+    destination: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+    args: &[DBusArg],
+) {
+    let Some(connection) = connect(bus).await else {
+        return;
+    };
+
+    info!(
+        "Calling D-Bus method {}.{} on {} {}",
+        interface, method, destination, path
+    );
+    if let Err(e) = call(&connection, destination, path, interface, method, args).await {
+        warn!(
+            "D-Bus call {}.{} on {} failed: {}",
+            interface, method, destination, e
+        );
+    }
+}
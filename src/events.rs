@@ -1,3 +1,4 @@
+use crate::power_source::PowerSource;
 use crate::state::KeyboardBacklightState;
 
 #[derive(Debug, Clone)]
@@ -5,4 +6,10 @@ pub enum Event {
     MicMuteLed(bool),
     Backlight(KeyboardBacklightState),
     SecondaryDisplay(bool),
+    PlatformProfile(String),
+    BatteryChargeLimit(u8),
+    FnLock(bool),
+    UsbKeyboardAttached(bool),
+    PowerSource(PowerSource),
+    Suspended(bool),
 }
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, KeyFunction, KeyFunctionContext};
+use crate::modifiers::Modifiers;
+
+/// The function keys on the top row of the Duo keyboard.
+/// They are reported through interface 4 over USB and as ABS_MISC events over Bluetooth.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FunctionKey {
+    KeyboardBacklight,
+    BrightnessDown,
+    BrightnessUp,
+    SwapUpDownDisplay,
+    MicrophoneMute,
+    EmojiPicker,
+    MyAsus,
+    ToggleSecondaryDisplay,
+}
+
+impl FunctionKey {
+    /// Map the key code of a keyboard report to a function key
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            199 => Some(Self::KeyboardBacklight),
+            16 => Some(Self::BrightnessDown),
+            32 => Some(Self::BrightnessUp),
+            156 => Some(Self::SwapUpDownDisplay),
+            124 => Some(Self::MicrophoneMute),
+            126 => Some(Self::EmojiPicker),
+            134 => Some(Self::MyAsus),
+            106 => Some(Self::ToggleSecondaryDisplay),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::KeyboardBacklight => "Backlight",
+            Self::BrightnessDown => "Brightness down",
+            Self::BrightnessUp => "Brightness up",
+            Self::SwapUpDownDisplay => "Swap up down display",
+            Self::MicrophoneMute => "Microphone mute",
+            Self::EmojiPicker => "Emoji picker",
+            Self::MyAsus => "MyASUS",
+            Self::ToggleSecondaryDisplay => "Toggle secondary display",
+        }
+    }
+}
+
+/// The key functions of a function key, optionally overridden while a modifier is held, e.g.
+/// `[myasus_key]` with `[myasus_key.shift]`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FunctionKeyBinding {
+    #[serde(flatten)]
+    pub function: KeyFunction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shift: Option<KeyFunction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ctrl: Option<KeyFunction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<KeyFunction>,
+}
+
+impl From<KeyFunction> for FunctionKeyBinding {
+    fn from(function: KeyFunction) -> Self {
+        Self {
+            function,
+            shift: None,
+            ctrl: None,
+            alt: None,
+        }
+    }
+}
+
+impl FunctionKeyBinding {
+    /// Pick the key function for the held modifiers.
+    /// When multiple modifiers with a binding are held, ctrl wins over alt, which wins over shift.
+    pub fn resolve(&self, modifiers: Modifiers) -> &KeyFunction {
+        let overrides = [
+            (modifiers.ctrl, &self.ctrl),
+            (modifiers.alt, &self.alt),
+            (modifiers.shift, &self.shift),
+        ];
+        overrides
+            .into_iter()
+            .find_map(|(held, function)| function.as_ref().filter(|_| held))
+            .unwrap_or(&self.function)
+    }
+
+    /// All key functions of this binding, used to enable the bound keys on the virtual keyboard
+    pub fn functions(&self) -> impl Iterator<Item = &KeyFunction> {
+        [Some(&self.function)]
+            .into_iter()
+            .chain([&self.shift, &self.ctrl, &self.alt].map(Option::as_ref))
+            .flatten()
+    }
+}
+
+#[derive(Default)]
+struct KeyPressHistory {
+    /// Last press whose action was executed, suppressed presses don't extend the suppression
+    last_execution: Option<Instant>,
+    debounced: u64,
+    rate_limited: u64,
+}
+
+/// Suppresses bouncing reports and too frequent executions of a function key, e.g. caused by
+/// a flaky connection between the keyboard and the laptop.
+/// Clone this to share across multiple components.
+#[derive(Clone)]
+pub struct KeyRateLimiter {
+    debounce: Duration,
+    min_interval: Duration,
+    history: Arc<Mutex<HashMap<FunctionKey, KeyPressHistory>>>,
+}
+
+impl KeyRateLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            debounce: Duration::from_millis(config.debounce_ms),
+            min_interval: Duration::from_millis(config.min_action_interval_ms),
+            history: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Record a press of `key`, returns whether its action should be executed.
+    /// A press within the debounce time of the previous executed press is dropped as a bounce,
+    /// a later press within the minimum interval is dropped as too frequent.
+    fn allow(&self, key: FunctionKey) -> bool {
+        let now = Instant::now();
+        let mut history = self.history.lock().unwrap();
+        let entry = history.entry(key).or_default();
+        let since_last_execution = entry.last_execution.map(|last| now.duration_since(last));
+
+        if since_last_execution.is_some_and(|elapsed| elapsed < self.debounce) {
+            entry.debounced += 1;
+            debug!(
+                "Debounced {} key press ({} debounced, {} rate limited so far)",
+                key.name(),
+                entry.debounced,
+                entry.rate_limited
+            );
+            return false;
+        }
+
+        if since_last_execution.is_some_and(|elapsed| elapsed < self.min_interval) {
+            entry.rate_limited += 1;
+            debug!(
+                "Rate limited {} key press ({} debounced, {} rate limited so far)",
+                key.name(),
+                entry.debounced,
+                entry.rate_limited
+            );
+            return false;
+        }
+
+        entry.last_execution = Some(now);
+        true
+    }
+}
+
+/// Handle a function key report from either the USB or the Bluetooth keyboard.
+/// `code` is 0 when the previously pressed key is released.
+pub async fn handle_function_key(code: i32, config: &Config, ctx: &KeyFunctionContext) {
+    let source = ctx.source.into();
+    // Only one function key can be pressed at a time, this is a hardware limitation
+    if code == 0 {
+        debug!("No key pressed");
+        ctx.state_manager.record(source, "Key released");
+        if let Some(layer) = &ctx.layer {
+            layer.deactivate();
+        }
+        if let Err(e) = ctx.virtual_keyboard.release_all_keys() {
+            warn!("Failed to release keys: {}", e);
+        }
+        return;
+    }
+
+    match FunctionKey::from_code(code) {
+        Some(key) if config.layer_key == Some(key) => {
+            let message = format!("{} key pressed, activating layer", key.name());
+            debug!("{}", message);
+            ctx.state_manager.record(source, message);
+            if let Some(layer) = &ctx.layer {
+                layer.activate();
+            }
+        }
+        Some(key) if !ctx.rate_limiter.allow(key) => {
+            ctx.state_manager
+                .record(source, format!("{} key press suppressed", key.name()));
+        }
+        Some(key) => {
+            let modifiers = ctx.modifiers.get();
+            let message = format!("{} key pressed with {:?}", key.name(), modifiers);
+            debug!("{}", message);
+            ctx.state_manager.record(source, message);
+            config
+                .function_key_binding(key)
+                .resolve(modifiers)
+                .execute(ctx)
+                .await;
+        }
+        None => {
+            let message = format!("Unknown key pressed: {}", code);
+            debug!("{}", message);
+            ctx.state_manager.record(source, message);
+            if let Err(e) = ctx.virtual_keyboard.release_all_keys() {
+                warn!("Failed to release keys: {}", e);
+            }
+        }
+    }
+}
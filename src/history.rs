@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::events::Event;
+use crate::state::KeyboardStateManager;
+use crate::virtual_keyboard::KeyboardSource;
+
+/// Number of entries kept, older entries are dropped
+const HISTORY_CAPACITY: usize = 1000;
+
+/// Where a history entry originated
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HistorySource {
+    /// Event broadcast to the consumers
+    Event,
+    /// Transition of the daemon state, e.g. idle or suspend
+    State,
+    /// The keyboard attached over USB
+    Usb,
+    /// The keyboard connected over Bluetooth
+    Bluetooth,
+    /// Command received through the control pipe
+    Pipe,
+    /// Firmware request received through the control socket
+    ControlSocket,
+}
+
+impl From<KeyboardSource> for HistorySource {
+    fn from(source: KeyboardSource) -> Self {
+        match source {
+            KeyboardSource::Usb => Self::Usb,
+            KeyboardSource::Bluetooth => Self::Bluetooth,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HistoryEntry {
+    /// Milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    pub source: HistorySource,
+    pub message: String,
+}
+
+/// Bounded, timestamped history of what happened in the daemon, for debugging.
+/// Clone this to share across multiple components.
+#[derive(Clone, Default)]
+pub struct EventHistory {
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+}
+
+impl EventHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, source: HistorySource, message: String) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == HISTORY_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(HistoryEntry {
+            timestamp_ms,
+            source,
+            message,
+        });
+    }
+
+    /// All entries, oldest first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().cloned().collect()
+    }
+}
+
+/// History consumer - records every event broadcast
+pub fn start_history_task(
+    state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match event_receiver.recv().await {
+                Ok(event) => {
+                    state_manager.record(HistorySource::Event, format!("{:?}", event));
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    state_manager
+                        .record(HistorySource::Event, format!("{} events skipped", skipped));
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,122 @@
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
+
+use log::{info, warn};
+use reqwest::Method;
+
+use crate::state::KeyboardStateManager;
+
+/// Shared by all requests so connections are reused between key presses
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// Replace `{name}` placeholders in `template` with the current daemon state.
+/// Available placeholders: backlight, mic_mute_led, secondary_display, usb_attached,
+/// platform_profile and battery_charge_limit.
+fn render_template(template: &str, state_manager: &KeyboardStateManager) -> String {
+    let values = [
+        (
+            "backlight",
+            state_manager.get_keyboard_backlight().name().to_string(),
+        ),
+        ("mic_mute_led", state_manager.get_mic_mute_led().to_string()),
+        (
+            "secondary_display",
+            state_manager.is_secondary_display_enabled().to_string(),
+        ),
+        (
+            "usb_attached",
+            state_manager.is_usb_keyboard_attached().to_string(),
+        ),
+        (
+            "platform_profile",
+            state_manager.get_platform_profile().unwrap_or_default(),
+        ),
+        (
+            "battery_charge_limit",
+            state_manager
+                .get_battery_charge_limit()
+                .map(|limit| limit.to_string())
+                .unwrap_or_default(),
+        ),
+    ];
+
+    let mut rendered = template.to_string();
+    for (name, value) in values {
+        rendered = rendered.replace(&format!("{{{}}}", name), &value);
+    }
+    rendered
+}
+
+async fn send(
+    method: &Method,
+    url: &str,
+    headers: &BTreeMap<String, String>,
+    body: &str,
+    timeout: Option<Duration>,
+) -> Result<reqwest::StatusCode, reqwest::Error> {
+    let mut request = CLIENT.request(method.clone(), url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    if !body.is_empty() {
+        request = request.body(body.to_string());
+    }
+    Ok(request.send().await?.status())
+}
+
+/// Send an HTTP request in the background.
+/// Failed requests (connection errors, timeouts and 5xx responses) are retried up to `retries` times
+/// with an exponential backoff starting at 500ms. A `timeout_seconds` of 0 disables the timeout.
+pub fn send_http_request(
+    method: &str,
+    url: &str,
+    headers: &BTreeMap<String, String>,
+    body: &str,
+    timeout_seconds: u64,
+    retries: u32,
+    state_manager: &KeyboardStateManager,
+) {
+    let method = match Method::from_bytes(method.to_uppercase().as_bytes()) {
+        Ok(method) => method,
+        Err(_) => {
+            warn!("Invalid HTTP method: {}", method);
+            return;
+        }
+    };
+    let timeout = (timeout_seconds > 0).then(|| Duration::from_secs(timeout_seconds));
+    let url = url.to_string();
+    let headers = headers.clone();
+    let body = render_template(body, state_manager);
+
+    info!("Sending HTTP request: {} {}", method, url);
+    tokio::spawn(async move {
+        let mut backoff = Duration::from_millis(500);
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+
+            match send(&method, &url, &headers, &body, timeout).await {
+                Ok(status) if status.is_server_error() => {
+                    warn!("HTTP request {} {} returned {}", method, url, status);
+                }
+                Ok(status) => {
+                    info!("HTTP request {} {} returned {}", method, url, status);
+                    return;
+                }
+                Err(e) => {
+                    warn!("HTTP request {} {} failed: {}", method, url, e);
+                }
+            }
+        }
+        warn!(
+            "Giving up on HTTP request {} {} after {} attempts",
+            method,
+            url,
+            retries + 1
+        );
+    });
+}
@@ -7,12 +7,18 @@ use log::{debug, info, warn};
 use nix::libc;
 use tokio::{
     fs,
-    sync::mpsc,
+    sync::{broadcast, mpsc},
     task::spawn_blocking,
     time::{Instant, sleep},
 };
 
-use crate::{config::Config, state::KeyboardStateManager};
+use crate::{
+    config::Config,
+    events::Event,
+    modifiers::ModifierState,
+    state::{IdleState, KeyboardStateManager},
+    virtual_keyboard::KeyboardSource,
+};
 
 /// Handle to notify the idle detection system of activity.
 /// Clone this to share across multiple components.
@@ -23,21 +29,21 @@ pub struct ActivityNotifier {
 
 impl ActivityNotifier {
     /// Notify that activity occurred, resetting the idle timer.
-    /// If the system was idle, this will trigger `idle_end`.
+    /// If the system was dimmed or idle, this restores the backlight.
     pub fn notify(&self) {
         let _ = self.tx.send(());
     }
 }
 
 /// Starts the idle detection task that monitors keyboard activity.
+/// The keyboard listeners also track the held modifiers into `modifiers`.
 /// Returns an `ActivityNotifier` that can be used to reset the idle timer from other code.
-/// Returns `None` if idle detection is disabled (idle_timeout_seconds = 0).
 pub fn start_idle_detection_task(
     config: &Config,
     state_manager: KeyboardStateManager,
+    modifiers: ModifierState,
+    event_receiver: broadcast::Receiver<Event>,
 ) -> ActivityNotifier {
-    let idle_timeout = Duration::from_secs(config.idle_timeout_seconds);
-
     // Channel for activity notifications
     let (activity_tx, activity_rx) = mpsc::unbounded_channel::<()>();
 
@@ -45,35 +51,88 @@ pub fn start_idle_detection_task(
         tx: activity_tx.clone(),
     };
 
-    if config.idle_timeout_seconds == 0 {
-        info!("Idle detection disabled (idle_timeout_seconds = 0)");
-        return notifier;
-    }
-
-    // Spawn the idle state manager task
+    // Spawn the idle state manager task, it always runs since the timeouts depend on the power source and connection mode
+    let config = config.clone();
     tokio::spawn(async move {
-        idle_state_task(idle_timeout, activity_rx, state_manager).await;
+        idle_state_task(config, activity_rx, state_manager, event_receiver).await;
     });
 
     // Spawn the device monitor task
     tokio::spawn(async move {
-        device_monitor_task(activity_tx).await;
+        device_monitor_task(activity_tx, modifiers).await;
     });
 
     notifier
 }
 
-/// Task that manages idle state based on activity events
+/// Idle stages for the current power source and connection mode.
+/// Each stage is entered after its timeout has passed since the previous stage.
+fn current_idle_stages(
+    config: &Config,
+    state_manager: &KeyboardStateManager,
+) -> Vec<(Duration, IdleState)> {
+    let connection = if state_manager.is_usb_keyboard_attached() {
+        KeyboardSource::Usb
+    } else {
+        KeyboardSource::Bluetooth
+    };
+    let (dim_timeout, idle_timeout) =
+        config.idle_timeouts(state_manager.get_power_source(), connection);
+
+    let mut idle_stages = Vec::new();
+    if dim_timeout != 0 {
+        idle_stages.push((Duration::from_secs(dim_timeout), IdleState::Dimmed));
+    }
+    if idle_timeout != 0 {
+        idle_stages.push((Duration::from_secs(idle_timeout), IdleState::Idle));
+    }
+    info!(
+        "Idle timeouts for {:?} on {:?}: dim after {}s, off after {}s (0 = disabled)",
+        connection,
+        state_manager.get_power_source(),
+        dim_timeout,
+        idle_timeout
+    );
+    idle_stages
+}
+
+/// The idle state after being inactive for `inactive`, and the time until the next stage is entered
+fn idle_state_after(
+    idle_stages: &[(Duration, IdleState)],
+    inactive: Duration,
+) -> (IdleState, Option<Duration>) {
+    let mut idle_state = IdleState::Active;
+    let mut stage_end = Duration::ZERO;
+    for (timeout, stage) in idle_stages {
+        stage_end += *timeout;
+        if inactive < stage_end {
+            return (idle_state, Some(stage_end - inactive));
+        }
+        idle_state = *stage;
+    }
+    (idle_state, None)
+}
+
+/// Task that manages idle state based on activity events.
+/// The stage is derived from the time since the last activity, so changed timeouts apply immediately.
 async fn idle_state_task(
-    idle_timeout: Duration,
+    config: Config,
     mut activity_rx: mpsc::UnboundedReceiver<()>,
     state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
 ) {
-    let mut is_idle = false;
+    let mut idle_stages = current_idle_stages(&config, &state_manager);
+    let mut idle_state = IdleState::Active;
     let mut last_activity = Instant::now();
 
     loop {
-        let time_until_idle = idle_timeout.saturating_sub(last_activity.elapsed());
+        let (new_idle_state, time_until_next_stage) =
+            idle_state_after(&idle_stages, last_activity.elapsed());
+        if new_idle_state != idle_state {
+            debug!("Idle state changed to {:?}", new_idle_state);
+            state_manager.set_idle_state(new_idle_state);
+            idle_state = new_idle_state;
+        }
 
         tokio::select! {
             // Wait for activity notification
@@ -81,11 +140,6 @@ async fn idle_state_task(
                 match result {
                     Some(()) => {
                         last_activity = Instant::now();
-                        if is_idle {
-                            debug!("Idle ended");
-                            state_manager.idle_end();
-                            is_idle = false;
-                        }
                     }
                     None => {
                         // Channel closed, all senders dropped
@@ -94,18 +148,34 @@ async fn idle_state_task(
                     }
                 }
             }
-            // Wait for idle timeout
-            _ = sleep(time_until_idle), if !is_idle => {
-                debug!("Idle detected");
-                state_manager.idle_start();
-                is_idle = true;
+            // Re-arm when the applicable timeouts change
+            result = event_receiver.recv() => {
+                match result {
+                    Ok(Event::PowerSource(power_source)) => {
+                        debug!("Power source changed to {:?}", power_source);
+                        idle_stages = current_idle_stages(&config, &state_manager);
+                    }
+                    Ok(Event::UsbKeyboardAttached(attached)) => {
+                        debug!("USB keyboard attached: {}", attached);
+                        idle_stages = current_idle_stages(&config, &state_manager);
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        idle_stages = current_idle_stages(&config, &state_manager);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return;
+                    }
+                }
             }
+            // Wait for the timeout of the next idle stage
+            _ = sleep(time_until_next_stage.unwrap_or_default()), if time_until_next_stage.is_some() => {}
         }
     }
 }
 
 /// Task that monitors /dev/input/ for keyboard devices and spawns listeners
-async fn device_monitor_task(activity_tx: mpsc::UnboundedSender<()>) {
+async fn device_monitor_task(activity_tx: mpsc::UnboundedSender<()>, modifiers: ModifierState) {
     // Check existing devices
     let mut entries = match fs::read_dir("/dev/input").await {
         Ok(entries) => entries,
@@ -117,7 +187,7 @@ async fn device_monitor_task(activity_tx: mpsc::UnboundedSender<()>) {
 
     while let Ok(Some(entry)) = entries.next_entry().await {
         let path = entry.path();
-        try_start_keyboard_listener(&path, activity_tx.clone()).await;
+        try_start_keyboard_listener(&path, activity_tx.clone(), modifiers.clone()).await;
     }
 
     // Watch for new devices using inotify
@@ -131,21 +201,23 @@ async fn device_monitor_task(activity_tx: mpsc::UnboundedSender<()>) {
     let mut stream = inotify.into_event_stream(&mut buffer).unwrap();
 
     while let Some(event_result) = stream.next().await {
-        if let Ok(event) = event_result {
-            if let Some(name) = event.name {
-                if event.mask.contains(inotify::EventMask::CREATE) {
-                    if name.to_str().unwrap_or("").starts_with("event") {
-                        let path = PathBuf::from("/dev/input/").join(name);
-                        try_start_keyboard_listener(&path, activity_tx.clone()).await;
-                    }
-                }
-            }
+        if let Ok(event) = event_result
+            && let Some(name) = event.name
+            && event.mask.contains(inotify::EventMask::CREATE)
+            && name.to_str().unwrap_or("").starts_with("event")
+        {
+            let path = PathBuf::from("/dev/input/").join(name);
+            try_start_keyboard_listener(&path, activity_tx.clone(), modifiers.clone()).await;
         }
     }
 }
 
 /// Attempts to start a keyboard listener for the given device path
-async fn try_start_keyboard_listener(path: &PathBuf, activity_tx: mpsc::UnboundedSender<()>) {
+async fn try_start_keyboard_listener(
+    path: &PathBuf,
+    activity_tx: mpsc::UnboundedSender<()>,
+    modifiers: ModifierState,
+) {
     // Check if path is a directory
     if let Ok(metadata) = fs::metadata(&path).await {
         if metadata.is_dir() {
@@ -171,10 +243,7 @@ async fn try_start_keyboard_listener(path: &PathBuf, activity_tx: mpsc::Unbounde
             Ok(f) => f,
             Err(_) => return None,
         };
-        match Device::new_from_file(file) {
-            Ok(d) => Some(d),
-            Err(_) => None,
-        }
+        Device::new_from_file(file).ok()
     })
     .await;
 
@@ -186,35 +255,39 @@ async fn try_start_keyboard_listener(path: &PathBuf, activity_tx: mpsc::Unbounde
     let device_name = device.name().unwrap_or("");
     if device_name.contains("ASUS Zenbook Duo Keyboard") {
         info!(
-            "Starting idle detection listener on {} ({})",
+            "Starting keyboard listener on {} ({})",
             path.display(),
             device_name
         );
 
-        start_keyboard_listener(path.clone(), device, activity_tx);
+        start_keyboard_listener(path.clone(), device, activity_tx, modifiers);
     }
 }
 
 /// Spawns a thread that listens to events from a keyboard device
 /// Uses a regular thread because device.next_event is blocking
-fn start_keyboard_listener(path: PathBuf, device: Device, activity_tx: mpsc::UnboundedSender<()>) {
+fn start_keyboard_listener(
+    path: PathBuf,
+    device: Device,
+    activity_tx: mpsc::UnboundedSender<()>,
+    modifiers: ModifierState,
+) {
     thread::spawn(move || {
         loop {
             match device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING) {
-                Ok(_) => {
-                    // Notify of activity
-                    if activity_tx.send(()).is_err() {
-                        // Receiver dropped, stop listening
-                        return;
-                    }
+                Ok((_status, event)) => {
+                    modifiers.update(&event);
+                    // Notify of activity, the receiver is dropped when idle detection is disabled
+                    let _ = activity_tx.send(());
                     debug!("Activity detected on {}", path.display());
                 }
                 Err(e) => {
                     if let Some(libc::ENODEV) = e.raw_os_error() {
                         info!(
-                            "Keyboard device {} disconnected. Stopping idle listener.",
+                            "Keyboard device {} disconnected. Stopping keyboard listener.",
                             path.display()
                         );
+                        modifiers.clear();
                         return;
                     } else {
                         warn!("Failed to read event from {}: {:?}", path.display(), e);
@@ -225,3 +298,66 @@ fn start_keyboard_listener(path: PathBuf, device: Device, activity_tx: mpsc::Unb
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STAGES: [(Duration, IdleState); 2] = [
+        (Duration::from_secs(10), IdleState::Dimmed),
+        (Duration::from_secs(20), IdleState::Idle),
+    ];
+
+    #[test]
+    fn active_before_the_first_stage() {
+        assert_eq!(
+            idle_state_after(&STAGES, Duration::from_secs(4)),
+            (IdleState::Active, Some(Duration::from_secs(6)))
+        );
+    }
+
+    #[test]
+    fn stage_timeouts_add_up() {
+        assert_eq!(
+            idle_state_after(&STAGES, Duration::from_secs(10)),
+            (IdleState::Dimmed, Some(Duration::from_secs(20)))
+        );
+        assert_eq!(
+            idle_state_after(&STAGES, Duration::from_secs(29)),
+            (IdleState::Dimmed, Some(Duration::from_secs(1)))
+        );
+    }
+
+    #[test]
+    fn last_stage_has_no_next_stage() {
+        assert_eq!(
+            idle_state_after(&STAGES, Duration::from_secs(30)),
+            (IdleState::Idle, None)
+        );
+        assert_eq!(
+            idle_state_after(&STAGES, Duration::from_secs(3600)),
+            (IdleState::Idle, None)
+        );
+    }
+
+    #[test]
+    fn no_stages_stays_active() {
+        assert_eq!(
+            idle_state_after(&[], Duration::from_secs(3600)),
+            (IdleState::Active, None)
+        );
+    }
+
+    #[test]
+    fn skipped_dim_stage() {
+        let stages = [(Duration::from_secs(300), IdleState::Idle)];
+        assert_eq!(
+            idle_state_after(&stages, Duration::from_secs(100)),
+            (IdleState::Active, Some(Duration::from_secs(200)))
+        );
+        assert_eq!(
+            idle_state_after(&stages, Duration::from_secs(300)),
+            (IdleState::Idle, None)
+        );
+    }
+}
@@ -6,27 +6,26 @@ use evdev_rs::{
 };
 use futures::stream::StreamExt;
 use inotify::{Inotify, WatchMask};
-use log::{debug, info, warn};
+use log::{info, warn};
 use nix::libc;
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::broadcast;
 use tokio::{fs, task::spawn_blocking};
 
 use crate::{
-    config::Config, events::Event, idle_detection::ActivityNotifier, state::KeyboardStateManager,
-    virtual_keyboard::VirtualKeyboard,
+    config::{Config, KeyFunctionContext},
+    events::Event,
+    function_key::handle_function_key,
+    idle_detection::ActivityNotifier,
 };
 
 pub fn start_bt_keyboard_monitor_task(
     config: &Config,
     event_sender: broadcast::Sender<Event>,
-    virtual_keyboard: Arc<Mutex<VirtualKeyboard>>,
-    state_manager: KeyboardStateManager,
+    ctx: KeyFunctionContext,
     activity_notifier: ActivityNotifier,
 ) {
     // First, check existing devices
     let config_clone = config.clone();
-    let virtual_keyboard_clone = virtual_keyboard.clone();
-    let state_manager_clone = state_manager.clone();
 
     tokio::spawn(async move {
         // Check existing devices using async read_dir
@@ -44,8 +43,7 @@ pub fn start_bt_keyboard_monitor_task(
                 &config_clone,
                 path,
                 event_sender.subscribe(),
-                virtual_keyboard_clone.clone(),
-                state_manager_clone.clone(),
+                ctx.clone(),
                 activity_notifier.clone(),
             )
             .await;
@@ -62,24 +60,21 @@ pub fn start_bt_keyboard_monitor_task(
         let mut stream = inotify.into_event_stream(&mut buffer).unwrap();
 
         while let Some(event_result) = stream.next().await {
-            if let Ok(event) = event_result {
-                if let Some(name) = event.name {
-                    if event.mask.contains(inotify::EventMask::CREATE) {
-                        if name.to_str().unwrap_or("").starts_with("event") {
-                            let path = PathBuf::from("/dev/input/").join(name);
-                            // there may be multiple event files for the same keyboard, so multiple tasks may be started
-                            try_start_bt_keyboard_task(
-                                &config_clone,
-                                path,
-                                event_sender.subscribe(),
-                                virtual_keyboard_clone.clone(),
-                                state_manager_clone.clone(),
-                                activity_notifier.clone(),
-                            )
-                            .await;
-                        }
-                    }
-                }
+            if let Ok(event) = event_result
+                && let Some(name) = event.name
+                && event.mask.contains(inotify::EventMask::CREATE)
+                && name.to_str().unwrap_or("").starts_with("event")
+            {
+                let path = PathBuf::from("/dev/input/").join(name);
+                // there may be multiple event files for the same keyboard, so multiple tasks may be started
+                try_start_bt_keyboard_task(
+                    &config_clone,
+                    path,
+                    event_sender.subscribe(),
+                    ctx.clone(),
+                    activity_notifier.clone(),
+                )
+                .await;
             }
         }
     });
@@ -89,8 +84,7 @@ async fn try_start_bt_keyboard_task(
     config: &Config,
     path: PathBuf,
     event_receiver: broadcast::Receiver<Event>,
-    virtual_keyboard: Arc<Mutex<VirtualKeyboard>>,
-    state_manager: KeyboardStateManager,
+    ctx: KeyFunctionContext,
     activity_notifier: ActivityNotifier,
 ) {
     // Check if path is a directory using async metadata
@@ -121,15 +115,7 @@ async fn try_start_bt_keyboard_task(
 
     // This name only matches when the keyboard is connected via Bluetooth, which is desired.
     if input.name() == Some("ASUS Zenbook Duo Keyboard") {
-        start_bt_keyboard_task(
-            config,
-            path,
-            input,
-            event_receiver,
-            virtual_keyboard,
-            state_manager,
-            activity_notifier,
-        );
+        start_bt_keyboard_task(config, path, input, event_receiver, ctx, activity_notifier);
     }
 }
 
@@ -138,12 +124,13 @@ pub fn start_bt_keyboard_task(
     path: PathBuf,
     keyboard: Device,
     mut event_receiver: broadcast::Receiver<Event>,
-    virtual_keyboard: Arc<Mutex<VirtualKeyboard>>,
-    state_manager: KeyboardStateManager,
+    ctx: KeyFunctionContext,
     activity_notifier: ActivityNotifier,
 ) {
     info!("Bluetooth connected on {}", path.display());
     activity_notifier.notify();
+    let path = path.display().to_string();
+    ctx.state_manager.add_bluetooth_keyboard(path.clone());
 
     // Create a cancellation token for the control task
     let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
@@ -196,12 +183,15 @@ pub fn start_bt_keyboard_task(
 
             match result {
                 Ok((_status, event)) => {
-                    parse_keyboard_event(event, &config, &virtual_keyboard, &state_manager).await;
+                    parse_keyboard_event(event, &config, &ctx).await;
                 }
                 Err(e) => {
                     if let Some(libc::ENODEV) = e.raw_os_error() {
                         info!("Bluetooth device disconnected. Exiting task.");
-                        virtual_keyboard.lock().await.release_all_keys();
+                        ctx.state_manager.remove_bluetooth_keyboard(&path);
+                        if let Err(e) = ctx.virtual_keyboard.release_all_keys() {
+                            warn!("Failed to release keys: {}", e);
+                        }
                         drop(shutdown_tx);
                         return;
                     } else {
@@ -214,79 +204,8 @@ pub fn start_bt_keyboard_task(
     });
 }
 
-async fn parse_keyboard_event(
-    event: InputEvent,
-    config: &Config,
-    virtual_keyboard: &Arc<Mutex<VirtualKeyboard>>,
-    state_manager: &KeyboardStateManager,
-) {
-    // Only one function key can be pressed at a time, this is a hardware limitation
+async fn parse_keyboard_event(event: InputEvent, config: &Config, ctx: &KeyFunctionContext) {
     if event.event_code == EventCode::EV_ABS(EV_ABS::ABS_MISC) {
-        match event.value {
-            0 => {
-                debug!("No key pressed");
-                virtual_keyboard.lock().await.release_all_keys();
-            }
-            199 => {
-                debug!("Backlight key pressed");
-                config
-                    .keyboard_backlight_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            16 => {
-                debug!("Brightness down key pressed");
-                config
-                    .brightness_down_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            32 => {
-                debug!("Brightness up key pressed");
-                config
-                    .brightness_up_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            156 => {
-                debug!("Swap up down display key pressed");
-                config
-                    .swap_up_down_display_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            124 => {
-                debug!("Microphone mute key pressed");
-                config
-                    .microphone_mute_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            126 => {
-                debug!("Emoji picker key pressed");
-                config
-                    .emoji_picker_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            134 => {
-                debug!("MyASUS key pressed");
-                config
-                    .myasus_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            106 => {
-                debug!("Toggle secondary display key pressed");
-                config
-                    .toggle_secondary_display_key
-                    .execute(&virtual_keyboard, &state_manager)
-                    .await;
-            }
-            _ => {
-                debug!("Unknown key pressed: {:?}", event);
-                virtual_keyboard.lock().await.release_all_keys();
-            }
-        }
+        handle_function_key(event.value, config, ctx).await;
     }
 }
@@ -7,11 +7,15 @@ use nusb::{
     hotplug::HotplugEvent,
     transfer::{ControlOut, ControlType, In, Interrupt, Recipient},
 };
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::broadcast;
 
 use crate::{
-    KeyboardBacklightState, config::Config, events::Event, idle_detection::ActivityNotifier,
-    parse_hex_string, state::KeyboardStateManager, virtual_keyboard::VirtualKeyboard,
+    config::{Config, KeyFunctionContext},
+    events::Event,
+    function_key::handle_function_key,
+    idle_detection::ActivityNotifier,
+    parse_hex_string,
+    state::KeyboardBacklightState,
 };
 
 pub async fn find_wired_keyboard(config: &Config) -> Option<DeviceInfo> {
@@ -26,8 +30,7 @@ pub fn start_usb_keyboard_monitor_task(
     config: &Config,
     mut current_keyboard: Option<(DeviceId, broadcast::Sender<()>)>,
     event_sender: broadcast::Sender<Event>,
-    virtual_keyboard: Arc<Mutex<VirtualKeyboard>>,
-    state_manager: KeyboardStateManager,
+    ctx: KeyFunctionContext,
     activity_notifier: ActivityNotifier,
 ) {
     let config = config.clone();
@@ -45,8 +48,7 @@ pub fn start_usb_keyboard_monitor_task(
                             &config,
                             device,
                             event_sender.subscribe(),
-                            virtual_keyboard.clone(),
-                            state_manager.clone(),
+                            ctx.clone(),
                             activity_notifier.clone(),
                         )
                         .await,
@@ -70,47 +72,29 @@ pub async fn start_usb_keyboard_task(
     config: &Config,
     keyboard: DeviceInfo,
     mut event_receiver: broadcast::Receiver<Event>,
-    virtual_keyboard: Arc<Mutex<VirtualKeyboard>>,
-    state_manager: KeyboardStateManager,
+    ctx: KeyFunctionContext,
     activity_notifier: ActivityNotifier,
 ) -> (DeviceId, broadcast::Sender<()>) {
     let (shutdown_tx, mut shutdown_rx1) = broadcast::channel::<()>(1);
     let device_id = keyboard.id();
 
     let keyboard_device = Arc::new(keyboard.open().await.unwrap());
-    state_manager.set_usb_keyboard_attached(true);
+    ctx.state_manager.set_usb_keyboard_attached(true);
     activity_notifier.notify();
     info!("USB connected");
 
     let interface_4 = keyboard_device.detach_and_claim_interface(4).await.unwrap();
     let mut endpoint_5 = interface_4.endpoint::<Interrupt, In>(0x85).unwrap();
 
-    // enable fn keys
-    keyboard_device
-        .control_out(
-            ControlOut {
-                control_type: ControlType::Class,
-                recipient: Recipient::Interface,
-                request: 0x09,
-                value: 0x035a,
-                index: 4,
-                data: &if config.fn_lock {
-                    parse_hex_string("5ad04e00000000000000000000000000")
-                } else {
-                    parse_hex_string("5ad04e01000000000000000000000000")
-                },
-            },
-            Duration::from_millis(100),
-        )
-        .await
-        .unwrap();
+    // Restore fn lock state
+    send_fn_lock_state(&keyboard_device, ctx.state_manager.get_fn_lock()).await;
 
     // Restore backlight state
-    let backlight_state = state_manager.get_keyboard_backlight();
+    let backlight_state = ctx.state_manager.get_keyboard_backlight();
     send_backlight_state(&keyboard_device, backlight_state).await;
 
     // Restore mic mute LED state
-    let mic_mute_state = state_manager.get_mic_mute_led();
+    let mic_mute_state = ctx.state_manager.get_mic_mute_led();
     send_mute_microphone_state(&keyboard_device, mic_mute_state).await;
 
     // Create a cancellation token for the control task
@@ -133,6 +117,9 @@ pub async fn start_usb_keyboard_task(
                         Ok(Event::MicMuteLed(enabled)) => {
                             send_mute_microphone_state(&keyboard_device2, enabled).await;
                         }
+                        Ok(Event::FnLock(enabled)) => {
+                            send_fn_lock_state(&keyboard_device2, enabled).await;
+                        }
                         Ok(_) => {
                             // dont care about other events
                         }
@@ -159,8 +146,10 @@ pub async fn start_usb_keyboard_task(
             tokio::select! {
                 _ = shutdown_rx2.recv() => {
                     info!("USB receive task shutting down");
-                    state_manager.set_usb_keyboard_attached(false);
-                    virtual_keyboard.lock().await.release_all_keys();
+                    ctx.state_manager.set_usb_keyboard_attached(false);
+                    if let Err(e) = ctx.virtual_keyboard.release_all_keys() {
+                        warn!("Failed to release keys: {}", e);
+                    }
                     break;
                 }
                 completion = endpoint_5.next_complete() => {
@@ -169,8 +158,7 @@ pub async fn start_usb_keyboard_task(
                             let data = &completion.buffer[..completion.actual_len];
                             // endpoint 5 is not a HID device so the idle detection module needs to be notified manually
                             activity_notifier.notify();
-                            parse_keyboard_data(data, &config, &virtual_keyboard, &state_manager)
-                                .await;
+                            parse_keyboard_data(data, &config, &ctx).await;
                         }
                         Err(e) => {
                             warn!("USB error: {:?}", e);
@@ -186,77 +174,16 @@ pub async fn start_usb_keyboard_task(
     (device_id, shutdown_tx)
 }
 
-async fn parse_keyboard_data(
-    data: &[u8],
-    config: &Config,
-    virtual_keyboard: &Arc<Mutex<VirtualKeyboard>>,
-    state_manager: &KeyboardStateManager,
-) {
-    // Only one function key can be pressed at a time, this is a hardware limitation
+async fn parse_keyboard_data(data: &[u8], config: &Config, ctx: &KeyFunctionContext) {
     match data {
-        [90, 0, 0, 0, 0, 0] => {
-            debug!("No key pressed");
-            virtual_keyboard.lock().await.release_all_keys();
-        }
-        [90, 199, 0, 0, 0, 0] => {
-            debug!("Backlight key pressed");
-            config
-                .keyboard_backlight_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 16, 0, 0, 0, 0] => {
-            debug!("Brightness down key pressed");
-            config
-                .brightness_down_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 32, 0, 0, 0, 0] => {
-            debug!("Brightness up key pressed");
-            config
-                .brightness_up_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 156, 0, 0, 0, 0] => {
-            debug!("Swap up down display key pressed");
-            config
-                .swap_up_down_display_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 124, 0, 0, 0, 0] => {
-            debug!("Microphone mute key pressed");
-            config
-                .microphone_mute_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 126, 0, 0, 0, 0] => {
-            debug!("Emoji picker key pressed");
-            config
-                .emoji_picker_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 134, 0, 0, 0, 0] => {
-            debug!("MyASUS key pressed");
-            config
-                .myasus_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
-        }
-        [90, 106, 0, 0, 0, 0] => {
-            debug!("Toggle secondary display key pressed");
-            config
-                .toggle_secondary_display_key
-                .execute(&virtual_keyboard, &state_manager)
-                .await;
+        [90, code, 0, 0, 0, 0] => {
+            handle_function_key(*code as i32, config, ctx).await;
         }
         _ => {
             debug!("Unknown key pressed: {:?}", data);
-            virtual_keyboard.lock().await.release_all_keys();
+            if let Err(e) = ctx.virtual_keyboard.release_all_keys() {
+                warn!("Failed to release keys: {}", e);
+            }
         }
     }
 }
@@ -287,6 +214,32 @@ async fn send_backlight_state(keyboard: &Arc<Device>, state: KeyboardBacklightSt
     }
 }
 
+async fn send_fn_lock_state(keyboard: &Arc<Device>, enabled: bool) {
+    let data = if enabled {
+        // F1-F12 need Fn
+        parse_hex_string("5ad04e00000000000000000000000000")
+    } else {
+        parse_hex_string("5ad04e01000000000000000000000000")
+    };
+
+    if let Err(e) = keyboard
+        .control_out(
+            ControlOut {
+                control_type: ControlType::Class,
+                recipient: Recipient::Interface,
+                request: 0x09,
+                value: 0x035a,
+                index: 4,
+                data: &data,
+            },
+            Duration::from_millis(100),
+        )
+        .await
+    {
+        warn!("Failed to send fn lock state: {:?}", e);
+    }
+}
+
 async fn send_mute_microphone_state(keyboard: &Arc<Device>, state: bool) {
     let data = if state {
         // turn on microphone mute led
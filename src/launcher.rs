@@ -0,0 +1,234 @@
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use tokio::fs;
+use users::{get_user_by_uid, os::unix::UserExt as _};
+use zbus::Proxy;
+
+use crate::user_session::{connect_session_bus, find_user_runtime_file};
+
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+/// Read the environment of the user's systemd manager, which desktops populate with the session environment
+async fn user_manager_environment() -> Vec<String> {
+    let Some(connection) = connect_session_bus().await else {
+        return Vec::new();
+    };
+    let proxy = match Proxy::new(
+        &connection,
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        "org.freedesktop.systemd1.Manager",
+    )
+    .await
+    {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            warn!("Failed to connect to the user's systemd manager: {}", e);
+            return Vec::new();
+        }
+    };
+    proxy
+        .get_property::<Vec<String>>("Environment")
+        .await
+        .unwrap_or_default()
+}
+
+/// Data directories to search for desktop entries in order of precedence,
+/// following the XDG base directory specification
+async fn user_data_dirs(home_dir: &Path) -> Vec<PathBuf> {
+    let environment = user_manager_environment().await;
+    let get_env = |name: &str| {
+        environment
+            .iter()
+            .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut dirs = vec![match get_env("XDG_DATA_HOME") {
+        Some(data_home) => PathBuf::from(data_home),
+        None => home_dir.join(".local/share"),
+    }];
+    dirs.extend(
+        get_env("XDG_DATA_DIRS")
+            .unwrap_or(DEFAULT_DATA_DIRS)
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+    );
+    dirs
+}
+
+/// Get the Exec key of the [Desktop Entry] group, with the string escapes (`\s`, `\n`, ...) resolved
+fn parse_exec(contents: &str) -> Option<String> {
+    let mut in_desktop_entry = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_desktop_entry = line == "[Desktop Entry]";
+        } else if in_desktop_entry
+            && let Some(value) = line.strip_prefix("Exec")
+            && let Some(value) = value.trim_start().strip_prefix('=')
+        {
+            return Some(unescape_string(value.trim()));
+        }
+    }
+    None
+}
+
+/// Resolve the escape sequences of a desktop entry string value
+fn unescape_string(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => result.push(' '),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// Split an Exec value into the program and its arguments following the desktop entry spec.
+/// Arguments are separated by spaces and can be quoted with `"`, inside quotes a backslash
+/// escapes the next character. Field codes (%f, %U, ...) are removed since no files or URLs
+/// are passed, arguments only made of field codes are dropped and `%%` becomes `%`.
+/// Returns None for an unterminated quote.
+fn exec_argv(exec: &str) -> Option<Vec<String>> {
+    let mut argv = Vec::new();
+    let mut arg = String::new();
+    // set for quoted arguments so empty ones ("") are kept
+    let mut has_arg = false;
+    let mut quoted = false;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' => quoted = false,
+                '\\' => arg.push(chars.next()?),
+                c => arg.push(c),
+            }
+            continue;
+        }
+        match c {
+            ' ' | '\t' | '\n' => {
+                if has_arg || !arg.is_empty() {
+                    argv.push(std::mem::take(&mut arg));
+                }
+                has_arg = false;
+            }
+            '"' => {
+                quoted = true;
+                has_arg = true;
+            }
+            '%' => {
+                if chars.next() == Some('%') {
+                    arg.push('%');
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if has_arg || !arg.is_empty() {
+        argv.push(arg);
+    }
+    Some(argv)
+}
+
+/// Find the file of the desktop entry `file_name` in an applications directory.
+/// Desktop ids map `-` to subdirectories, e.g. "org-foo.desktop" can be "org/foo.desktop".
+fn find_desktop_file(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let path = dir.join(file_name);
+    if path.is_file() {
+        return Some(path);
+    }
+    file_name
+        .match_indices('-')
+        .filter(|(i, _)| dir.join(&file_name[..*i]).is_dir())
+        .find_map(|(i, _)| find_desktop_file(&dir.join(&file_name[..i]), &file_name[i + 1..]))
+}
+
+/// Launch the application with the desktop entry `desktop_id` (e.g. "org.gnome.Nautilus.desktop")
+/// as the logged in user, in the user's session
+pub async fn launch_desktop_entry(desktop_id: &str) {
+    let Some((uid, _)) = find_user_runtime_file("bus").await else {
+        warn!("Failed to launch {}: no logged in user found", desktop_id);
+        return;
+    };
+    let Some(user) = get_user_by_uid(uid) else {
+        warn!("Failed to launch {}: no user with uid {}", desktop_id, uid);
+        return;
+    };
+
+    let file_name = if desktop_id.ends_with(".desktop") {
+        desktop_id.to_string()
+    } else {
+        format!("{}.desktop", desktop_id)
+    };
+
+    let mut exec = None;
+    for dir in user_data_dirs(user.home_dir()).await {
+        let Some(path) = find_desktop_file(&dir.join("applications"), &file_name) else {
+            continue;
+        };
+        if let Ok(contents) = fs::read_to_string(&path).await {
+            exec = parse_exec(&contents);
+            if exec.is_some() {
+                break;
+            }
+        }
+    }
+    let Some(exec) = exec else {
+        warn!("Failed to find a desktop entry for {}", desktop_id);
+        return;
+    };
+
+    let argv = match exec_argv(&exec) {
+        Some(argv) if !argv.is_empty() => argv,
+        _ => {
+            warn!(
+                "Failed to launch {}: invalid Exec value {}",
+                desktop_id, exec
+            );
+            return;
+        }
+    };
+    let machine = format!("{}@", user.name().to_string_lossy());
+    info!("Launching {} as {}: {:?}", desktop_id, machine, argv);
+    match tokio::process::Command::new("systemd-run")
+        .args([
+            "--user",
+            "--machine",
+            machine.as_str(),
+            "--collect",
+            "--quiet",
+        ])
+        .arg("--")
+        .args(&argv)
+        .output()
+        .await
+    {
+        Ok(output) if !output.status.success() => {
+            warn!(
+                "Failed to launch {}: {}",
+                desktop_id,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            warn!("Failed to launch {}: {}", desktop_id, e);
+        }
+    }
+}
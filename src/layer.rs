@@ -0,0 +1,301 @@
+use std::{
+    fs::OpenOptions,
+    io::{Read as _, Write as _},
+    os::{
+        fd::AsFd as _,
+        unix::{fs::OpenOptionsExt as _, net::UnixStream},
+    },
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use evdev_rs::{
+    Device, DeviceWrapper as _, GrabMode, InputEvent, ReadFlag,
+    enums::{EV_ABS, EV_KEY, EventCode},
+};
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    libc,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    idle_detection::ActivityNotifier, modifiers::ModifierState, virtual_keyboard::VirtualKeyboard,
+};
+
+/// Matches the main event node of the keyboard, both over USB and Bluetooth
+const KEYBOARD_NAME: &str = "Zenbook Duo Keyboard";
+
+/// Maps a key of the regular keyboard to other keys while the layer key is held
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LayerBinding {
+    pub key: EV_KEY,
+    pub keys: Vec<EV_KEY>,
+}
+
+/// While the layer is active, the keyboard's main event node is grabbed so that keys with a layer binding
+/// emit their alternate mapping through the virtual keyboard, other keys are passed through unchanged.
+/// A single grab thread per manager owns the grab, activations are sent to it over a socket
+/// so that a quick release and press can't race two grabs.
+/// Clone this to share across multiple components.
+#[derive(Clone)]
+pub struct LayerManager {
+    commands: Arc<StdMutex<UnixStream>>,
+}
+
+struct GrabThread {
+    bindings: Vec<LayerBinding>,
+    virtual_keyboard: VirtualKeyboard,
+    activity_notifier: ActivityNotifier,
+    modifiers: ModifierState,
+    commands: UnixStream,
+    /// Grabbed keyboards, empty while the layer is inactive
+    keyboards: Vec<Device>,
+    /// Keys pressed on the grabbed keyboards while the layer is active
+    pressed_keys: Vec<EV_KEY>,
+}
+
+impl LayerManager {
+    pub fn new(
+        bindings: Vec<LayerBinding>,
+        virtual_keyboard: VirtualKeyboard,
+        activity_notifier: ActivityNotifier,
+        modifiers: ModifierState,
+    ) -> Self {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut grab_thread = GrabThread {
+            bindings,
+            virtual_keyboard,
+            activity_notifier,
+            modifiers,
+            commands: receiver,
+            keyboards: Vec::new(),
+            pressed_keys: Vec::new(),
+        };
+        // evdev reads are blocking, run the grab loop in its own thread
+        std::thread::spawn(move || grab_thread.run());
+        Self {
+            commands: Arc::new(StdMutex::new(sender)),
+        }
+    }
+
+    fn send(&self, active: bool) {
+        let mut commands = self.commands.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = commands.write_all(&[active as u8]) {
+            warn!("Failed to send layer command: {}", e);
+        }
+    }
+
+    /// Called when the layer key is pressed
+    pub fn activate(&self) {
+        self.send(true);
+    }
+
+    /// Called when the layer key is released
+    pub fn deactivate(&self) {
+        self.send(false);
+    }
+}
+
+impl GrabThread {
+    fn run(&mut self) {
+        loop {
+            let (commands_ready, ready_keyboards) = match self.wait() {
+                Ok(ready) => ready,
+                Err(e) => {
+                    warn!("Failed to poll layer keyboards, deactivating layer: {}", e);
+                    self.deactivate();
+                    continue;
+                }
+            };
+
+            if commands_ready {
+                let mut buffer = [0; 64];
+                match self.commands.read(&mut buffer) {
+                    // the sockets are closed when the manager is dropped
+                    Ok(0) => {
+                        self.deactivate();
+                        return;
+                    }
+                    // only the latest command matters, e.g. a release and press in quick succession
+                    // keep the layer active
+                    Ok(n) if buffer[n - 1] != 0 => self.activate(),
+                    Ok(_) => self.deactivate(),
+                    Err(e) => warn!("Failed to read layer command: {}", e),
+                }
+            }
+
+            for i in ready_keyboards {
+                if !self.read_events(i) {
+                    self.deactivate();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Block until a command arrives or a grabbed keyboard has events.
+    /// Returns whether there are commands and the indices of the keyboards with events.
+    fn wait(&self) -> nix::Result<(bool, Vec<usize>)> {
+        let mut fds = vec![PollFd::new(self.commands.as_fd(), PollFlags::POLLIN)];
+        fds.extend(
+            self.keyboards
+                .iter()
+                .map(|keyboard| PollFd::new(keyboard.file().as_fd(), PollFlags::POLLIN)),
+        );
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) => {}
+            Err(Errno::EINTR) => return Ok((false, Vec::new())),
+            Err(e) => return Err(e),
+        }
+        let is_ready = |fd: &PollFd| fd.revents().is_some_and(|revents| !revents.is_empty());
+        let ready_keyboards = fds[1..]
+            .iter()
+            .enumerate()
+            .filter(|(_, fd)| is_ready(fd))
+            .map(|(i, _)| i)
+            .collect();
+        Ok((is_ready(&fds[0]), ready_keyboards))
+    }
+
+    fn activate(&mut self) {
+        if !self.keyboards.is_empty() {
+            return;
+        }
+        let mut keyboards = find_keyboards();
+        keyboards.retain_mut(|keyboard| match keyboard.grab(GrabMode::Grab) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to grab keyboard: {}", e);
+                false
+            }
+        });
+        if keyboards.is_empty() {
+            warn!("No keyboard to grab, layer not activated");
+            return;
+        }
+        self.keyboards = keyboards;
+        info!("Layer activated");
+    }
+
+    fn deactivate(&mut self) {
+        if self.keyboards.is_empty() {
+            return;
+        }
+        for keyboard in &mut self.keyboards {
+            let _ = keyboard.grab(GrabMode::Ungrab);
+        }
+        self.keyboards.clear();
+        self.pressed_keys.clear();
+        if let Err(e) = self.virtual_keyboard.release_all_keys() {
+            warn!("Failed to release layer keys: {}", e);
+        }
+        info!("Layer deactivated");
+    }
+
+    /// Handle the pending events of the keyboard at `index`, returns false when the layer should be deactivated
+    fn read_events(&mut self, index: usize) -> bool {
+        loop {
+            match self.keyboards[index].next_event(ReadFlag::NORMAL) {
+                Ok((_status, event)) => {
+                    if !self.handle_event(event) {
+                        return false;
+                    }
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => return true,
+                Err(e) => {
+                    warn!("Failed to read keyboard event, deactivating layer: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Returns false when the layer should be deactivated
+    fn handle_event(&mut self, event: InputEvent) -> bool {
+        // the regular keyboard listeners don't see events of grabbed keyboards
+        self.modifiers.update(&event);
+        match event.event_code {
+            // Over Bluetooth the function key release is reported on the grabbed node,
+            // so the Bluetooth keyboard task never sees it
+            EventCode::EV_ABS(EV_ABS::ABS_MISC) if event.value == 0 => {
+                return false;
+            }
+            EventCode::EV_KEY(key) => {
+                self.activity_notifier.notify();
+                // keys without a layer binding, e.g. modifiers, are passed through unchanged
+                let keys = match self.bindings.iter().find(|binding| binding.key == key) {
+                    Some(binding) => binding.keys.as_slice(),
+                    None => std::slice::from_ref(&key),
+                };
+                let virtual_keyboard = &self.virtual_keyboard;
+                let result = match event.value {
+                    1 => {
+                        if !self.pressed_keys.contains(&key) {
+                            self.pressed_keys.push(key);
+                        }
+                        virtual_keyboard.press_keys(keys)
+                    }
+                    0 if self.pressed_keys.contains(&key) => {
+                        self.pressed_keys.retain(|pressed| *pressed != key);
+                        virtual_keyboard.release_keys(keys)
+                    }
+                    // held before the layer was activated, the compositor saw the press on the keyboard
+                    // itself and would never see the release
+                    0 => virtual_keyboard.forward_release(key),
+                    _ => {
+                        // key repeat, the compositor generates its own
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("Failed to send layer keys {:?}: {}", keys, e);
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+/// Open the keyboard's event nodes that report regular keys, non-blocking
+fn find_keyboards() -> Vec<Device> {
+    let entries = match std::fs::read_dir("/dev/input") {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read /dev/input: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut keyboards = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("event"))
+        {
+            continue;
+        }
+        let Ok(file) = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+        else {
+            continue;
+        };
+        let Ok(device) = Device::new_from_file(file) else {
+            continue;
+        };
+        if device
+            .name()
+            .is_some_and(|name| name.contains(KEYBOARD_NAME))
+            && device.has_event_code(&EventCode::EV_KEY(EV_KEY::KEY_A))
+        {
+            keyboards.push(device);
+        }
+    }
+    keyboards
+}
@@ -1,21 +1,37 @@
 use std::panic;
-use std::time::Duration;
-use std::{path::PathBuf, process, sync::Arc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
 
+use nix::libc;
 use tokio::fs;
+use tokio::io::AsyncWriteExt as _;
 use tokio::signal::unix::{SignalKind, signal};
-use tokio::sync::{Mutex, broadcast};
+use tokio::sync::broadcast;
 
-use crate::mute_state::start_listen_mute_state_thread;
+use crate::mute_state::{AudioController, start_listen_mute_state_thread};
 use crate::{
-    config::{Config, DEFAULT_CONFIG_PATH},
+    ambient_light::start_ambient_light_task,
+    battery::start_battery_task,
+    config::{Config, DEFAULT_CONFIG_PATH, DEFAULT_SOCKET_PATH, KeyFunctionContext},
+    control_socket::{query, start_control_socket_task},
     events::Event,
+    function_key::KeyRateLimiter,
+    history::start_history_task,
     idle_detection::start_idle_detection_task,
     keyboard_usb::{find_wired_keyboard, start_usb_keyboard_monitor_task, start_usb_keyboard_task},
+    layer::LayerManager,
+    modifiers::ModifierState,
+    persisted_state::{restore_persisted_state, start_state_persistence_task},
+    platform_profile::start_platform_profile_task,
+    power_source::start_power_source_task,
+    schedule::start_schedule_task,
     secondary_display::start_secondary_display_task,
-    state::{KeyboardBacklightState, KeyboardStateManager},
+    state::{BacklightLevels, KeyboardStateManager},
     unix_pipe::start_receive_commands_task,
-    virtual_keyboard::VirtualKeyboard,
+    virtual_keyboard::{KeyboardSource, VirtualKeyboard},
 };
 use clap::Parser;
 use keyboard_bt::start_bt_keyboard_monitor_task;
@@ -36,17 +52,47 @@ enum Args {
         #[arg(short, long)]
         config_path: Option<PathBuf>,
     },
+    /// Print the state of the running daemon as JSON
+    Status {
+        /// Path to the config file, defaults to /etc/zenbook-duo-daemon/config.toml
+        #[arg(short, long)]
+        config_path: Option<PathBuf>,
+    },
+    /// Collect state, event history, config and logs into an archive in the current directory
+    BugReport {
+        /// Path to the config file, defaults to /etc/zenbook-duo-daemon/config.toml
+        #[arg(short, long)]
+        config_path: Option<PathBuf>,
+    },
 }
 
+mod ambient_light;
+mod battery;
+mod brightness;
 mod config;
+mod control_socket;
+mod dbus_call;
 mod events;
+mod function_key;
+mod history;
+mod http;
 mod idle_detection;
 mod keyboard_bt;
 mod keyboard_usb;
+mod launcher;
+mod layer;
+mod modifiers;
+mod mpris;
 mod mute_state;
+mod persisted_state;
+mod platform_profile;
+mod power_source;
+mod schedule;
 mod secondary_display;
 mod state;
+mod toggle;
 mod unix_pipe;
+mod user_session;
 mod virtual_keyboard;
 
 #[tokio::main(flavor = "current_thread")]
@@ -63,6 +109,188 @@ async fn main() {
         Args::Run { config_path } => {
             run_daemon(config_path.unwrap_or(PathBuf::from(DEFAULT_CONFIG_PATH))).await;
         }
+        Args::Status { config_path } => {
+            print_status(config_path.unwrap_or(PathBuf::from(DEFAULT_CONFIG_PATH))).await;
+        }
+        Args::BugReport { config_path } => {
+            create_bug_report(config_path.unwrap_or(PathBuf::from(DEFAULT_CONFIG_PATH))).await;
+        }
+    }
+}
+
+/// The socket path is read from the config if possible, so this also works without root
+async fn socket_path(config_path: &PathBuf) -> PathBuf {
+    match Config::try_read(config_path).await {
+        Ok(config) => PathBuf::from(config.socket_path),
+        Err(_) => PathBuf::from(DEFAULT_SOCKET_PATH),
+    }
+}
+
+async fn print_status(config_path: PathBuf) {
+    let socket_path = socket_path(&config_path).await;
+    let response = match query(&socket_path, "status").await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!(
+                "Failed to connect to the daemon at {}: {}",
+                socket_path.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    println!("{}", pretty_json(&response));
+}
+
+/// Pretty-print JSON responses, anything else (e.g. errors) is kept as is
+fn pretty_json(response: &str) -> String {
+    match serde_json::from_str::<serde_json::Value>(response) {
+        Ok(value) => serde_json::to_string_pretty(&value).unwrap(),
+        Err(_) => response.trim_end().to_string(),
+    }
+}
+
+/// Output of `program`, or why it couldn't be run
+async fn command_output(program: &str, args: &[&str]) -> String {
+    match tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+    {
+        Ok(output) => format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => format!("Failed to run {}: {}", program, e),
+    }
+}
+
+/// Replace the values of Http key function headers, they often contain access tokens
+fn redact_headers(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if key == "headers"
+                    && let toml::Value::Table(headers) = value
+                {
+                    for (_, header) in headers.iter_mut() {
+                        *header = toml::Value::String("<redacted>".to_string());
+                    }
+                } else {
+                    redact_headers(value);
+                }
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(redact_headers),
+        _ => {}
+    }
+}
+
+/// The config file for the bug report, comments are dropped and header values are redacted
+async fn redacted_config(config_path: &Path) -> String {
+    let contents = match fs::read_to_string(config_path).await {
+        Ok(contents) => contents,
+        Err(e) => return format!("Failed to read {}: {}", config_path.display(), e),
+    };
+    let mut config = match contents.parse::<toml::Table>() {
+        Ok(config) => toml::Value::Table(config),
+        // the contents are left out since the secrets in it can't be redacted
+        Err(e) => return format!("Failed to parse {}: {}", config_path.display(), e),
+    };
+    redact_headers(&mut config);
+    toml::to_string_pretty(&config).unwrap_or_else(|e| format!("Failed to serialize config: {}", e))
+}
+
+/// Write a new file of the bug report, refusing to follow symlinks or overwrite existing files
+async fn write_report_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .await?;
+    file.write_all(contents.as_bytes()).await
+}
+
+async fn create_bug_report(config_path: PathBuf) {
+    let socket_path = socket_path(&config_path).await;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let name = format!("zenbook-duo-daemon-bug-report-{}", timestamp);
+    let dir = std::env::temp_dir().join(&name);
+    // the report is usually created as root in a world-writable directory, an existing directory
+    // could have been prepared by another user
+    if let Err(e) = fs::DirBuilder::new().mode(0o700).create(&dir).await {
+        eprintln!("Failed to create {}: {}", dir.display(), e);
+        process::exit(1);
+    }
+
+    let mut files = vec![
+        ("version.txt", env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "system.txt",
+            format!(
+                "{}\nboard: {}",
+                command_output("uname", &["-a"]).await.trim_end(),
+                fs::read_to_string("/sys/class/dmi/id/board_name")
+                    .await
+                    .unwrap_or_default()
+                    .trim()
+            ),
+        ),
+        (
+            "journal.txt",
+            command_output(
+                "journalctl",
+                &["-u", "zenbook-duo-daemon", "-b", "--no-pager"],
+            )
+            .await,
+        ),
+        ("config.toml", redacted_config(&config_path).await),
+    ];
+    // the daemon may not be running, which is worth reporting as well
+    for (request, file_name) in [("status", "status.json"), ("history", "history.json")] {
+        let response = match query(&socket_path, request).await {
+            Ok(response) => pretty_json(&response),
+            Err(e) => format!(
+                "Failed to connect to the daemon at {}: {}",
+                socket_path.display(),
+                e
+            ),
+        };
+        files.push((file_name, response));
+    }
+
+    for (file_name, contents) in files {
+        if let Err(e) = write_report_file(&dir.join(file_name), &contents).await {
+            eprintln!("Failed to write {}: {}", file_name, e);
+        }
+    }
+
+    let archive = format!("{}.tar.gz", name);
+    let status = tokio::process::Command::new("tar")
+        .arg("-czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(std::env::temp_dir())
+        .arg(&name)
+        .status()
+        .await;
+    fs::remove_dir_all(&dir).await.ok();
+    match status {
+        Ok(status) if status.success() => println!("Bug report written to {}", archive),
+        Ok(status) => {
+            eprintln!("tar exited with {}", status);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to run tar: {}", e);
+            process::exit(1);
+        }
     }
 }
 
@@ -106,30 +334,76 @@ async fn run_daemon(config_path: PathBuf) {
     // Create event channel
     let (event_sender, _) = broadcast::channel::<Event>(64);
 
-    // Create virtual keyboard
-    let virtual_keyboard = Arc::new(Mutex::new(VirtualKeyboard::new(&config)));
+    let wired_keyboard = find_wired_keyboard(&config).await;
+    let state_manager = KeyboardStateManager::new(
+        wired_keyboard.is_some(),
+        config.fn_lock,
+        BacklightLevels {
+            usb: config.usb_keyboard_backlight,
+            bluetooth: config.bluetooth_keyboard_backlight,
+        },
+        event_sender.clone(),
+    );
+    start_history_task(state_manager.clone(), event_sender.subscribe());
+    let modifiers = ModifierState::new();
+    start_power_source_task(state_manager.clone());
+    let activity_notifier = start_idle_detection_task(
+        &config,
+        state_manager.clone(),
+        modifiers.clone(),
+        event_sender.subscribe(),
+    );
+    restore_persisted_state(&state_manager).await;
+    start_state_persistence_task(state_manager.clone(), event_sender.subscribe());
+    start_ambient_light_task(&config, state_manager.clone());
+    start_schedule_task(&config, state_manager.clone(), event_sender.subscribe());
 
-    let (state_manager, activity_notifier, current_usb_keyboard) =
-        if let Some(keyboard) = find_wired_keyboard(&config).await {
-            let state_manager = KeyboardStateManager::new(true, event_sender.clone());
-            let activity_notifier = start_idle_detection_task(&config, state_manager.clone());
+    let audio_controller = AudioController::new();
+    let rate_limiter = KeyRateLimiter::new(&config);
+    // Each keyboard source gets its own virtual keyboard
+    let create_ctx = |source: KeyboardSource| {
+        let virtual_keyboard = match VirtualKeyboard::new(&config, source) {
+            Ok(virtual_keyboard) => virtual_keyboard,
+            Err(e) => {
+                error!("Failed to create the {:?} virtual keyboard: {}", source, e);
+                process::exit(1);
+            }
+        };
+        KeyFunctionContext {
+            source,
+            layer: config.layer_key.map(|_| {
+                LayerManager::new(
+                    config.layer_bindings.clone(),
+                    virtual_keyboard.clone(),
+                    activity_notifier.clone(),
+                    modifiers.clone(),
+                )
+            }),
+            virtual_keyboard,
+            modifiers: modifiers.clone(),
+            rate_limiter: rate_limiter.clone(),
+            state_manager: state_manager.clone(),
+            audio_controller: audio_controller.clone(),
+            config: config.clone(),
+        }
+    };
+    let usb_ctx = create_ctx(KeyboardSource::Usb);
+    let bt_ctx = create_ctx(KeyboardSource::Bluetooth);
 
-            let current_usb_keyboard = start_usb_keyboard_task(
+    let current_usb_keyboard = if let Some(keyboard) = wired_keyboard {
+        Some(
+            start_usb_keyboard_task(
                 &config,
                 keyboard,
                 event_sender.subscribe(),
-                virtual_keyboard.clone(),
-                state_manager.clone(),
+                usb_ctx.clone(),
                 activity_notifier.clone(),
             )
-            .await;
-            (state_manager, activity_notifier, Some(current_usb_keyboard))
-        } else {
-            let state_manager = KeyboardStateManager::new(false, event_sender.clone());
-            let activity_notifier = start_idle_detection_task(&config, state_manager.clone());
-
-            (state_manager, activity_notifier, None)
-        };
+            .await,
+        )
+    } else {
+        None
+    };
 
     start_secondary_display_task(
         config.clone(),
@@ -138,11 +412,14 @@ async fn run_daemon(config_path: PathBuf) {
     )
     .await;
 
+    start_platform_profile_task(state_manager.clone(), event_sender.subscribe()).await;
+
+    start_battery_task(&config, state_manager.clone(), event_sender.subscribe()).await;
+
     start_bt_keyboard_monitor_task(
         &config,
         event_sender.clone(),
-        virtual_keyboard.clone(),
-        state_manager.clone(),
+        bt_ctx,
         activity_notifier.clone(),
     );
 
@@ -150,14 +427,20 @@ async fn run_daemon(config_path: PathBuf) {
         &config,
         current_usb_keyboard,
         event_sender.clone(),
-        virtual_keyboard.clone(),
-        state_manager.clone(),
+        usb_ctx,
         activity_notifier.clone(),
     );
 
-    start_listen_mute_state_thread(state_manager.clone());
+    start_listen_mute_state_thread(state_manager.clone(), audio_controller.clone());
+
+    start_control_socket_task(&config, state_manager.clone());
 
-    start_receive_commands_task(&config, state_manager.clone(), activity_notifier.clone());
+    start_receive_commands_task(
+        &config,
+        state_manager.clone(),
+        activity_notifier.clone(),
+        audio_controller,
+    );
 
     panic::set_hook(Box::new(|info| {
         error!("Thread panicked: {info}");
@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use evdev_rs::{
+    InputEvent,
+    enums::{EV_KEY, EventCode},
+};
+
+/// Modifier keys held on the regular keyboard when a function key is pressed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+/// Tracks the modifier keys held on the keyboard's regular event nodes.
+/// Clone this to share across multiple components.
+#[derive(Clone, Default)]
+pub struct ModifierState {
+    held_keys: Arc<Mutex<Vec<EV_KEY>>>,
+}
+
+fn is_modifier(key: EV_KEY) -> bool {
+    matches!(
+        key,
+        EV_KEY::KEY_LEFTSHIFT
+            | EV_KEY::KEY_RIGHTSHIFT
+            | EV_KEY::KEY_LEFTCTRL
+            | EV_KEY::KEY_RIGHTCTRL
+            | EV_KEY::KEY_LEFTALT
+            | EV_KEY::KEY_RIGHTALT
+    )
+}
+
+impl ModifierState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the held modifiers from a keyboard event, other events are ignored
+    pub fn update(&self, event: &InputEvent) {
+        let EventCode::EV_KEY(key) = event.event_code else {
+            return;
+        };
+        if !is_modifier(key) {
+            return;
+        }
+
+        let mut held_keys = self.held_keys.lock().unwrap();
+        match event.value {
+            0 => held_keys.retain(|held| *held != key),
+            1 if !held_keys.contains(&key) => held_keys.push(key),
+            _ => {
+                // key repeat
+            }
+        }
+    }
+
+    /// Forget all held modifiers, e.g. when a keyboard disconnects while a modifier is down
+    pub fn clear(&self) {
+        self.held_keys.lock().unwrap().clear();
+    }
+
+    pub fn get(&self) -> Modifiers {
+        let held_keys = self.held_keys.lock().unwrap();
+        let is_held = |keys: [EV_KEY; 2]| keys.iter().any(|key| held_keys.contains(key));
+        Modifiers {
+            shift: is_held([EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_RIGHTSHIFT]),
+            ctrl: is_held([EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_RIGHTCTRL]),
+            alt: is_held([EV_KEY::KEY_LEFTALT, EV_KEY::KEY_RIGHTALT]),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use zbus::{Connection, Proxy};
+
+use crate::state::KeyboardStateManager;
+use crate::user_session::connect_session_bus;
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const MPRIS_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum MprisAction {
+    PlayPause,
+    Play,
+    Pause,
+    Stop,
+    Next,
+    Previous,
+    /// Bring the player window to the front
+    Raise,
+}
+
+impl MprisAction {
+    fn interface_and_method(&self) -> (&'static str, &'static str) {
+        match self {
+            Self::PlayPause => (MPRIS_PLAYER_INTERFACE, "PlayPause"),
+            Self::Play => (MPRIS_PLAYER_INTERFACE, "Play"),
+            Self::Pause => (MPRIS_PLAYER_INTERFACE, "Pause"),
+            Self::Stop => (MPRIS_PLAYER_INTERFACE, "Stop"),
+            Self::Next => (MPRIS_PLAYER_INTERFACE, "Next"),
+            Self::Previous => (MPRIS_PLAYER_INTERFACE, "Previous"),
+            Self::Raise => (MPRIS_INTERFACE, "Raise"),
+        }
+    }
+}
+
+async fn player_proxy<'a>(
+    connection: &Connection,
+    player: &'a str,
+    interface: &'static str,
+) -> zbus::Result<Proxy<'a>> {
+    Proxy::new(connection, player, MPRIS_PATH, interface).await
+}
+
+async fn list_players(connection: &Connection) -> zbus::Result<Vec<String>> {
+    let names = zbus::fdo::DBusProxy::new(connection)
+        .await?
+        .list_names()
+        .await?;
+    Ok(names
+        .into_iter()
+        .map(|name| name.to_string())
+        .filter(|name| name.starts_with(MPRIS_PREFIX))
+        .collect())
+}
+
+async fn is_playing(connection: &Connection, player: &str) -> bool {
+    match player_proxy(connection, player, MPRIS_PLAYER_INTERFACE).await {
+        Ok(proxy) => proxy
+            .get_property::<String>("PlaybackStatus")
+            .await
+            .is_ok_and(|status| status == "Playing"),
+        Err(_) => false,
+    }
+}
+
+/// Pick the player to control.
+/// The first running player in `preferred` wins, matched by the name after `org.mpris.MediaPlayer2.`,
+/// e.g. "spotify" or "firefox". Otherwise the most recently active player is used:
+/// a playing player, then the player controlled last, then any player.
+async fn select_player(
+    connection: &Connection,
+    preferred: &[String],
+    state_manager: &KeyboardStateManager,
+) -> zbus::Result<Option<String>> {
+    let players = list_players(connection).await?;
+
+    for name in preferred {
+        let prefix = format!("{}{}", MPRIS_PREFIX, name);
+        if let Some(player) = players.iter().find(|player| player.starts_with(&prefix)) {
+            return Ok(Some(player.clone()));
+        }
+    }
+
+    let last_player = state_manager
+        .get_last_media_player()
+        .filter(|player| players.contains(player));
+
+    let mut playing = Vec::new();
+    for player in &players {
+        if is_playing(connection, player).await {
+            playing.push(player.clone());
+        }
+    }
+    if let Some(player) = playing.first() {
+        if last_player
+            .as_ref()
+            .is_some_and(|last| playing.contains(last))
+        {
+            return Ok(last_player);
+        }
+        return Ok(Some(player.clone()));
+    }
+
+    Ok(last_player.or(players.into_iter().next()))
+}
+
+/// Run `action` on a media player of the logged in user, see `select_player` for how the player is picked
+pub async fn control_media_player(
+    action: MprisAction,
+    preferred: &[String],
+    state_manager: &KeyboardStateManager,
+) {
+    let Some(connection) = connect_session_bus().await else {
+        return;
+    };
+
+    let player = match select_player(&connection, preferred, state_manager).await {
+        Ok(Some(player)) => player,
+        Ok(None) => {
+            info!("No media player found");
+            return;
+        }
+        Err(e) => {
+            warn!("Failed to list media players: {}", e);
+            return;
+        }
+    };
+
+    debug!("Sending {:?} to media player {}", action, player);
+    let (interface, method) = action.interface_and_method();
+    let result = match player_proxy(&connection, &player, interface).await {
+        Ok(proxy) => proxy.call_method(method, &()).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => state_manager.set_last_media_player(player),
+        Err(e) => warn!(
+            "Failed to send {:?} to media player {}: {}",
+            action, player, e
+        ),
+    }
+}
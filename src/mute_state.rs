@@ -1,62 +1,163 @@
 use std::{
-    ffi::CString, fs, io::BufReader, os::unix::net::UnixStream, path::PathBuf, thread,
+    ffi::{CStr, CString},
+    io::BufReader,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use log::{info, warn};
 use pulseaudio::protocol::{self, DEFAULT_SOURCE, ProtocolError, SubscriptionEvent};
+use tokio::task::spawn_blocking;
 use users::{get_user_by_uid, os::unix::UserExt as _};
 
 use crate::state::KeyboardStateManager;
+use crate::user_session::find_user_runtime_file;
 
-pub fn start_listen_mute_state_thread(state_manager: KeyboardStateManager) {
-    thread::spawn(move || {
-        loop {
-            if let Some((uid, pa_socket_path)) = find_pulseaudio_socket_path() {
-                info!("Found pulseaudio socket path: {:?}", pa_socket_path);
-                if let Err(e) = listen_mute_state(pa_socket_path, uid, state_manager.clone()) {
-                    warn!("Error listening to mute state: {:?}", e);
+/// Handle to the pulseaudio connection opened by the mute state thread, used to issue commands.
+/// Clone this to share across multiple components.
+#[derive(Clone, Default)]
+pub struct AudioController {
+    client: Arc<Mutex<Option<PulseAudioClient>>>,
+}
+
+impl AudioController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Toggle the mute state of the default source (microphone).
+    /// The mic mute LED follows through the source subscription.
+    pub async fn toggle_default_source_mute(&self) {
+        self.with_client("toggle microphone mute", |client| {
+            let is_muted = client.get_is_default_source_muted()?;
+            client.set_default_source_muted(!is_muted)
+        })
+        .await;
+    }
+
+    /// Switch the default sink to the one after the current default sink.
+    /// Only the sinks listed in `sinks` are cycled through, unless it is empty.
+    /// If `move_streams` is set, all playing streams are moved to the new sink as well.
+    pub async fn cycle_default_sink(&self, sinks: Vec<String>, move_streams: bool) {
+        self.with_client("switch audio output", move |client| {
+            let available = client.list_sink_names()?;
+            let candidates: Vec<&CString> = if sinks.is_empty() {
+                available.iter().collect()
+            } else {
+                sinks
+                    .iter()
+                    .filter_map(|sink| {
+                        available
+                            .iter()
+                            .find(|name| name.to_str() == Ok(sink.as_str()))
+                    })
+                    .collect()
+            };
+
+            let current = client.get_default_sink_name()?;
+            let next = candidates
+                .iter()
+                .position(|name| Some(*name) == current.as_ref())
+                .and_then(|i| candidates.get(i + 1))
+                .or(candidates.first());
+
+            match next {
+                Some(next) => client.switch_default_sink(next, move_streams),
+                None => {
+                    warn!("No audio output to switch to");
+                    Ok(())
                 }
+            }
+        })
+        .await;
+    }
 
-                state_manager.set_mic_mute_led(false);
+    /// Switch the default sink to the sink named `sink`.
+    /// If `move_streams` is set, all playing streams are moved to the new sink as well.
+    pub async fn set_default_sink(&self, sink: String, move_streams: bool) {
+        self.with_client("switch audio output", move |client| {
+            let available = client.list_sink_names()?;
+            match available
+                .iter()
+                .find(|name| name.to_str() == Ok(sink.as_str()))
+            {
+                Some(name) => client.switch_default_sink(name, move_streams),
+                None => {
+                    warn!("Unknown audio output: {}", sink);
+                    Ok(())
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Run `f` on the command client in a blocking context, logging failures as "Failed to `action`"
+    async fn with_client<T, F>(&self, action: &'static str, f: F) -> Option<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PulseAudioClient) -> Result<T, ProtocolError> + Send + 'static,
+    {
+        let client = self.client.clone();
+        let result = spawn_blocking(move || client.lock().unwrap().as_mut().map(f))
+            .await
+            .unwrap();
+
+        match result {
+            Some(Ok(value)) => Some(value),
+            Some(Err(e)) => {
+                warn!("Failed to {}: {:?}", action, e);
+                None
+            }
+            None => {
+                warn!("Failed to {}: not connected to pulseaudio", action);
+                None
             }
-            thread::sleep(Duration::from_secs(1));
         }
-    });
+    }
 }
 
-fn find_pulseaudio_socket_path() -> Option<(u32, PathBuf)> {
-    if let Ok(entries) = fs::read_dir("/run/user") {
-        for entry in entries.flatten() {
-            if let Ok(file_type) = entry.file_type() {
-                if file_type.is_dir() {
-                    let uid_folder = entry.file_name();
-                    if let Ok(uid) = uid_folder.to_string_lossy().parse::<u32>() {
-                        if uid < 1000 || uid > 2000 {
-                            continue; // ignore system users
-                        }
-                        if get_user_by_uid(uid).is_none() {
-                            continue; // ignore users that are not found
-                        }
-                        let pa_socket_path = PathBuf::from(format!(
-                            "/run/user/{}/pulse/native",
-                            uid_folder.to_string_lossy()
-                        ));
-                        if pa_socket_path.exists() {
-                            return Some((uid, pa_socket_path));
-                        }
-                    }
+/// Keeps the mic mute LED in sync with the default source of the active user's pulseaudio server.
+/// The pulseaudio connection is blocking, so it runs on a blocking thread.
+pub fn start_listen_mute_state_thread(
+    state_manager: KeyboardStateManager,
+    audio_controller: AudioController,
+) {
+    tokio::spawn(async move {
+        loop {
+            if let Some((uid, pa_socket_path)) = find_user_runtime_file("pulse/native").await {
+                info!("Found pulseaudio socket path: {:?}", pa_socket_path);
+                let state_manager = state_manager.clone();
+                let audio_controller = audio_controller.clone();
+                let result = spawn_blocking(move || {
+                    let result = listen_mute_state(
+                        pa_socket_path,
+                        uid,
+                        state_manager.clone(),
+                        &audio_controller,
+                    );
+                    *audio_controller.client.lock().unwrap() = None;
+                    state_manager.set_mic_mute_led(false);
+                    result
+                })
+                .await;
+                match result {
+                    Ok(Err(e)) => warn!("Error listening to mute state: {:?}", e),
+                    Err(e) => warn!("Mute state listener failed: {}", e),
+                    Ok(Ok(())) => {}
                 }
             }
+            tokio::time::sleep(Duration::from_secs(1)).await;
         }
-    }
-    None
+    });
 }
 
 fn listen_mute_state(
     pa_socket_path: PathBuf,
     uid: u32,
     state_manager: KeyboardStateManager,
+    audio_controller: &AudioController,
 ) -> Result<(), ProtocolError> {
     let user = get_user_by_uid(uid).unwrap();
     let home_dir = user.home_dir();
@@ -64,14 +165,18 @@ fn listen_mute_state(
 
     if let Ok(cookie) = std::fs::read(&cookie_path) {
         let mut subscription_client = PulseAudioClient::new(&pa_socket_path, cookie.clone())?;
-        let mut get_muted_client = PulseAudioClient::new(&pa_socket_path, cookie)?;
+        let mut command_client = PulseAudioClient::new(&pa_socket_path, cookie)?;
 
-        let is_muted = get_muted_client.get_is_default_source_muted()?;
+        let is_muted = command_client.get_is_default_source_muted()?;
         state_manager.set_mic_mute_led(is_muted);
+        *audio_controller.client.lock().unwrap() = Some(command_client);
 
         subscription_client.subscribe_source_events(|_| {
-            let is_muted = get_muted_client.get_is_default_source_muted()?;
-            state_manager.set_mic_mute_led(is_muted);
+            let mut client = audio_controller.client.lock().unwrap();
+            if let Some(client) = client.as_mut() {
+                let is_muted = client.get_is_default_source_muted()?;
+                state_manager.set_mic_mute_led(is_muted);
+            }
             Ok(())
         })?;
     } else {
@@ -95,7 +200,7 @@ impl PulseAudioClient {
             version: pulseaudio::protocol::MAX_VERSION,
             supports_shm: false,
             supports_memfd: false,
-            cookie: cookie,
+            cookie,
         };
 
         protocol::write_command_message(
@@ -152,11 +257,8 @@ impl PulseAudioClient {
         loop {
             let (_, event) = protocol::read_command_message(&mut self.sock, self.protocol_version)?;
 
-            match event {
-                protocol::Command::SubscribeEvent(event) => {
-                    callback(event)?;
-                }
-                _ => {}
+            if let protocol::Command::SubscribeEvent(event) = event {
+                callback(event)?;
             }
         }
     }
@@ -179,4 +281,101 @@ impl PulseAudioClient {
         )?;
         Ok(response.muted)
     }
+
+    pub fn set_default_source_muted(&mut self, muted: bool) -> Result<(), ProtocolError> {
+        protocol::write_command_message(
+            self.sock.get_mut(),
+            self.seq,
+            &protocol::Command::SetSourceMute(protocol::SetDeviceMuteParams {
+                device_index: None,
+                device_name: Some(DEFAULT_SOURCE.into()),
+                mute: muted,
+            }),
+            self.protocol_version,
+        )?;
+        self.seq += 1;
+
+        let _ = protocol::read_ack_message(&mut self.sock)?;
+        Ok(())
+    }
+
+    pub fn list_sink_names(&mut self) -> Result<Vec<CString>, ProtocolError> {
+        protocol::write_command_message(
+            self.sock.get_mut(),
+            self.seq,
+            &protocol::Command::GetSinkInfoList,
+            self.protocol_version,
+        )?;
+        self.seq += 1;
+
+        let (_, response) = protocol::read_reply_message::<Vec<protocol::SinkInfo>>(
+            &mut self.sock,
+            self.protocol_version,
+        )?;
+        Ok(response.into_iter().map(|sink| sink.name).collect())
+    }
+
+    pub fn get_default_sink_name(&mut self) -> Result<Option<CString>, ProtocolError> {
+        protocol::write_command_message(
+            self.sock.get_mut(),
+            self.seq,
+            &protocol::Command::GetServerInfo,
+            self.protocol_version,
+        )?;
+        self.seq += 1;
+
+        let (_, response) = protocol::read_reply_message::<protocol::ServerInfo>(
+            &mut self.sock,
+            self.protocol_version,
+        )?;
+        Ok(response.default_sink_name)
+    }
+
+    /// Set the default sink, and optionally move all sink inputs (playing streams) to it
+    pub fn switch_default_sink(
+        &mut self,
+        name: &CStr,
+        move_streams: bool,
+    ) -> Result<(), ProtocolError> {
+        protocol::write_command_message(
+            self.sock.get_mut(),
+            self.seq,
+            &protocol::Command::SetDefaultSink(name.to_owned()),
+            self.protocol_version,
+        )?;
+        self.seq += 1;
+        let _ = protocol::read_ack_message(&mut self.sock)?;
+        info!("Switched audio output to {:?}", name);
+
+        if move_streams {
+            protocol::write_command_message(
+                self.sock.get_mut(),
+                self.seq,
+                &protocol::Command::GetSinkInputInfoList,
+                self.protocol_version,
+            )?;
+            self.seq += 1;
+            let (_, sink_inputs) = protocol::read_reply_message::<Vec<protocol::SinkInputInfo>>(
+                &mut self.sock,
+                self.protocol_version,
+            )?;
+
+            for sink_input in sink_inputs {
+                protocol::write_command_message(
+                    self.sock.get_mut(),
+                    self.seq,
+                    &protocol::Command::MoveSinkInput(protocol::MoveStreamParams {
+                        index: Some(sink_input.index),
+                        device_index: None,
+                        device_name: Some(name.to_owned()),
+                    }),
+                    self.protocol_version,
+                )?;
+                self.seq += 1;
+                let _ = protocol::read_ack_message(&mut self.sock)?;
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::AsyncWriteExt as _,
+    sync::{Mutex, broadcast},
+};
+
+use crate::events::Event;
+use crate::state::{BacklightLevels, KeyboardStateManager};
+
+const STATE_DIR: &str = "/var/lib/zenbook-duo-daemon";
+const STATE_PATH: &str = "/var/lib/zenbook-duo-daemon/state.toml";
+
+/// Serializes read-modify-write cycles of the state file
+static STATE_FILE_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Daemon state that survives restarts and reboots
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct PersistedState {
+    pub backlight_levels: Option<BacklightLevels>,
+    pub fn_lock: Option<bool>,
+    pub platform_profile: Option<String>,
+    /// secondary display state chosen manually while the keyboard is detached
+    pub secondary_display: Option<bool>,
+    /// state of the Toggle key functions with `persist = true`
+    pub toggles: BTreeMap<String, bool>,
+}
+
+async fn read_state_file() -> PersistedState {
+    match fs::read_to_string(STATE_PATH).await {
+        Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Failed to parse {}: {}", STATE_PATH, e);
+            PersistedState::default()
+        }),
+        Err(_) => PersistedState::default(),
+    }
+}
+
+/// Write the state to a temporary file and rename it over the state file,
+/// so a crash or power loss never leaves a partially written state file behind
+async fn write_state_file(state: &PersistedState) -> std::io::Result<()> {
+    fs::create_dir_all(STATE_DIR).await?;
+
+    let temp_path = format!("{}.tmp", STATE_PATH);
+    let contents = toml::to_string(state).map_err(std::io::Error::other)?;
+    let mut file = fs::File::create(&temp_path).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    fs::rename(&temp_path, STATE_PATH).await
+}
+
+pub async fn read_persisted_state() -> PersistedState {
+    let _lock = STATE_FILE_LOCK.lock().await;
+    read_state_file().await
+}
+
+/// Apply `update` to the persisted state, the state file is only written if something changed
+pub async fn update_persisted_state(update: impl FnOnce(&mut PersistedState)) {
+    let _lock = STATE_FILE_LOCK.lock().await;
+    let old_state = read_state_file().await;
+    let mut state = old_state.clone();
+    update(&mut state);
+
+    if state != old_state
+        && let Err(e) = write_state_file(&state).await
+    {
+        warn!("Failed to persist state to {}: {}", STATE_PATH, e);
+    }
+}
+
+/// Restore the persisted state into the state manager.
+/// The platform profile is restored by the platform profile task, since it depends on the firmware.
+pub async fn restore_persisted_state(state_manager: &KeyboardStateManager) {
+    let state = read_persisted_state().await;
+
+    if let Some(levels) = state.backlight_levels {
+        info!(
+            "Restored keyboard backlight: {} over USB, {} over Bluetooth",
+            levels.usb.name(),
+            levels.bluetooth.name()
+        );
+        state_manager.set_backlight_levels(levels);
+    }
+    if let Some(fn_lock) = state.fn_lock {
+        info!("Restored fn lock: {}", fn_lock);
+        state_manager.set_fn_lock(fn_lock);
+    }
+    if let Some(enabled) = state.secondary_display
+        && !state_manager.is_usb_keyboard_attached()
+    {
+        info!("Restored secondary display: {}", enabled);
+        state_manager.set_secondary_display(enabled);
+    }
+    for (name, enabled) in state.toggles {
+        info!(
+            "Restored toggle {}: {}",
+            name,
+            if enabled { "on" } else { "off" }
+        );
+        state_manager.set_toggle(&name, enabled);
+    }
+}
+
+/// State persistence consumer - writes changes of the persisted state to the state file.
+/// Toggles are persisted by the Toggle key function, as only some of them should be persisted.
+pub fn start_state_persistence_task(
+    state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
+) {
+    tokio::spawn(async move {
+        // the task starts after the persisted state was restored, so the state file has these levels
+        let mut persisted_levels = state_manager.get_backlight_levels();
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event::Backlight(_)) => {
+                    // the event is also sent when the backlight changes while idle, dimmed or automatic,
+                    // only changes of the selected levels are persisted
+                    let levels = state_manager.get_backlight_levels();
+                    if levels == persisted_levels {
+                        continue;
+                    }
+                    persisted_levels = levels;
+                    update_persisted_state(|state| state.backlight_levels = Some(levels)).await;
+                }
+                Ok(Event::FnLock(enabled)) => {
+                    update_persisted_state(|state| state.fn_lock = Some(enabled)).await;
+                }
+                Ok(Event::SecondaryDisplay(_)) => {
+                    let enabled = state_manager.get_secondary_display_override();
+                    update_persisted_state(|state| state.secondary_display = enabled).await;
+                }
+                Ok(Event::PlatformProfile(profile)) => {
+                    update_persisted_state(|state| state.platform_profile = Some(profile)).await;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,96 @@
+use log::{info, warn};
+use tokio::fs;
+use tokio::sync::broadcast;
+
+use crate::events::Event;
+use crate::persisted_state::read_persisted_state;
+use crate::state::KeyboardStateManager;
+
+const PLATFORM_PROFILE_PATH: &str = "/sys/firmware/acpi/platform_profile";
+const PLATFORM_PROFILE_CHOICES_PATH: &str = "/sys/firmware/acpi/platform_profile_choices";
+
+async fn read_choices() -> Vec<String> {
+    match fs::read_to_string(PLATFORM_PROFILE_CHOICES_PATH).await {
+        Ok(contents) => contents.split_whitespace().map(str::to_string).collect(),
+        Err(e) => {
+            warn!("Failed to read platform profile choices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+async fn read_current() -> Option<String> {
+    fs::read_to_string(PLATFORM_PROFILE_PATH)
+        .await
+        .ok()
+        .map(|contents| contents.trim().to_string())
+}
+
+async fn apply_profile(profile: &str) {
+    if let Err(e) = fs::write(PLATFORM_PROFILE_PATH, profile).await {
+        warn!("Failed to set platform profile to {}: {}", profile, e);
+    }
+}
+
+/// Switch to the platform profile after the current one in `platform_profile_choices`.
+/// The firmware value is preferred, since power-profiles-daemon or the firmware may have changed it.
+pub async fn cycle_platform_profile(state_manager: &KeyboardStateManager) {
+    let choices = read_choices().await;
+    let current = match read_current().await {
+        Some(profile) => Some(profile),
+        None => state_manager.get_platform_profile(),
+    };
+
+    let next = choices
+        .iter()
+        .position(|choice| Some(choice) == current.as_ref())
+        .and_then(|i| choices.get(i + 1))
+        .or(choices.first());
+
+    if let Some(next) = next {
+        state_manager.set_platform_profile(next.clone());
+    }
+}
+
+/// Switch to the platform profile named `profile`, if the platform supports it
+pub async fn set_platform_profile(state_manager: &KeyboardStateManager, profile: &str) {
+    if read_choices().await.iter().any(|choice| choice == profile) {
+        state_manager.set_platform_profile(profile.to_string());
+    } else {
+        warn!("Unsupported platform profile: {}", profile);
+    }
+}
+
+/// Platform profile consumer - writes the selected profile to the firmware
+pub async fn start_platform_profile_task(
+    state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
+) {
+    if !fs::try_exists(PLATFORM_PROFILE_PATH).await.unwrap_or(false) {
+        info!("Platform profile is not supported on this device");
+        return;
+    }
+
+    // Restore the profile chosen before the daemon was restarted
+    if let Some(profile) = read_persisted_state().await.platform_profile {
+        set_platform_profile(&state_manager, &profile).await;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event::PlatformProfile(profile)) => {
+                    info!("Setting platform profile to {}", profile);
+                    apply_profile(&profile).await;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    break;
+                }
+            }
+        }
+    });
+}
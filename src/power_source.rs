@@ -0,0 +1,178 @@
+use std::{
+    fs::File,
+    io::{self, Read as _},
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    time::Duration,
+};
+
+use log::{info, warn};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::unix::AsyncFd};
+
+use crate::battery::POWER_SUPPLY_PATH;
+use crate::state::KeyboardStateManager;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+/// Whether a power supply is an adapter powering the laptop, from its `type` and `scope` attributes.
+/// Batteries of peripherals, e.g. `hid-<address>-battery`, have the `Device` scope and are skipped.
+fn is_adapter(supply_type: &str, scope: Option<&str>) -> bool {
+    matches!(supply_type.trim(), "Mains" | "USB")
+        && scope.is_none_or(|scope| scope.trim() != "Device")
+}
+
+/// On AC if any adapter is online.
+/// Devices without adapters are treated as being on AC.
+async fn read_power_source() -> PowerSource {
+    let mut entries = match fs::read_dir(POWER_SUPPLY_PATH).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read {}: {}", POWER_SUPPLY_PATH, e);
+            return PowerSource::Ac;
+        }
+    };
+
+    let mut has_adapter = false;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let Ok(supply_type) = fs::read_to_string(path.join("type")).await else {
+            continue;
+        };
+        let scope = fs::read_to_string(path.join("scope")).await.ok();
+        if !is_adapter(&supply_type, scope.as_deref()) {
+            continue;
+        }
+        if let Ok(online) = fs::read_to_string(path.join("online")).await {
+            has_adapter = true;
+            if online.trim() == "1" {
+                return PowerSource::Ac;
+            }
+        }
+    }
+
+    if has_adapter {
+        PowerSource::Battery
+    } else {
+        PowerSource::Ac
+    }
+}
+
+/// Open a netlink socket receiving the kernel's uevents, the same ones udev receives
+fn open_uevent_socket() -> io::Result<File> {
+    // SAFETY: plain socket calls, the fd is owned by the returned file
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+
+        let mut addr: libc::sockaddr_nl = std::mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // the multicast group of kernel uevents
+        addr.nl_groups = 1;
+        if libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from(fd))
+    }
+}
+
+/// Whether a uevent message is about a power supply, e.g. the adapter being plugged in.
+/// Messages are a header followed by NUL separated KEY=value pairs.
+fn is_power_supply_uevent(message: &[u8]) -> bool {
+    message
+        .split(|byte| *byte == 0)
+        .any(|field| field == b"SUBSYSTEM=power_supply")
+}
+
+/// Wait for the next power supply uevent
+async fn next_power_supply_uevent(socket: &AsyncFd<File>) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let mut guard = socket.readable().await?;
+        match guard.try_io(|socket| socket.get_ref().read(&mut buffer)) {
+            Ok(Ok(len)) if is_power_supply_uevent(&buffer[..len]) => return Ok(()),
+            Ok(Ok(_)) => {}
+            // the receive buffer overflowed, some uevents may have been missed
+            Ok(Err(e)) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => {}
+        }
+    }
+}
+
+/// Task to keep the power source in the state up to date.
+/// sysfs attributes don't support inotify, so the power supply uevents are watched instead.
+/// The power supplies are polled if uevents aren't available.
+pub fn start_power_source_task(state_manager: KeyboardStateManager) {
+    tokio::spawn(async move {
+        state_manager.set_power_source(read_power_source().await);
+
+        match open_uevent_socket().and_then(AsyncFd::new) {
+            Ok(socket) => loop {
+                match next_power_supply_uevent(&socket).await {
+                    Ok(()) => state_manager.set_power_source(read_power_source().await),
+                    Err(e) => {
+                        warn!("Failed to receive uevents, polling the power source: {}", e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                warn!("Failed to watch uevents, polling the power source: {}", e);
+            }
+        }
+
+        info!("Polling the power source every 2 seconds");
+        let mut interval = tokio::time::interval(Duration::from_secs(2));
+        loop {
+            interval.tick().await;
+            state_manager.set_power_source(read_power_source().await);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_supply_uevent() {
+        let message = b"change@/devices/LNXSYSTM:00/ACPI0003:00/power_supply/ACAD\0ACTION=change\0\
+            DEVPATH=/devices/LNXSYSTM:00/ACPI0003:00/power_supply/ACAD\0SUBSYSTEM=power_supply\0\
+            POWER_SUPPLY_NAME=ACAD\0POWER_SUPPLY_ONLINE=1\0SEQNUM=4242\0";
+        assert!(is_power_supply_uevent(message));
+    }
+
+    #[test]
+    fn adapters() {
+        assert!(is_adapter("Mains\n", None));
+        assert!(is_adapter("USB\n", Some("System\n")));
+        assert!(!is_adapter("Battery\n", None));
+        // e.g. the battery of a Bluetooth mouse, which reports online=1
+        assert!(!is_adapter("Battery\n", Some("Device\n")));
+        assert!(!is_adapter("USB\n", Some("Device\n")));
+    }
+
+    #[test]
+    fn other_uevent() {
+        let message = b"add@/devices/virtual/input/input42\0ACTION=add\0SUBSYSTEM=input\0\
+            NAME=\"power_supply\"\0SEQNUM=4243\0";
+        assert!(!is_power_supply_uevent(message));
+    }
+}
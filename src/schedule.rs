@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::config::Config;
+use crate::events::Event;
+use crate::state::{KeyboardBacklightState, KeyboardStateManager};
+
+/// The monotonic clock stops during suspend, so the wall clock is checked at least this often
+/// to catch up on transitions missed while suspended without a `suspend_end` command
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Backlight level set at a time of day, until the next entry
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BacklightScheduleEntry {
+    /// Local time, e.g. "18:30"
+    #[serde(with = "hours_minutes")]
+    pub at: NaiveTime,
+    pub level: KeyboardBacklightState,
+}
+
+/// Times of day written as "HH:MM" in the config file
+mod hours_minutes {
+    use chrono::NaiveTime;
+    use serde::{Deserialize as _, Deserializer, Serializer, de::Error as _};
+
+    const FORMAT: &str = "%H:%M";
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&time.format(FORMAT))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&value, FORMAT).map_err(|e| {
+            D::Error::custom(format!("invalid time \"{}\", expected HH:MM: {}", value, e))
+        })
+    }
+}
+
+/// `time` on `date` in the timezone `tz`
+fn local_datetime<Tz: TimeZone>(tz: &Tz, date: NaiveDate, time: NaiveTime) -> Option<DateTime<Tz>> {
+    let naive = date.and_time(time);
+    // the earlier time is used when the clock is set back, times skipped when the clock
+    // is set forward happen at the first valid time after the jump
+    (0..=24 * 60)
+        .map(|minutes| naive + TimeDelta::minutes(minutes))
+        .find_map(|naive| tz.from_local_datetime(&naive).earliest())
+}
+
+/// The last transition at or before `now` with its level, and the time of the next transition
+fn transitions_around<Tz: TimeZone>(
+    entries: &[(NaiveTime, KeyboardBacklightState)],
+    now: &DateTime<Tz>,
+) -> Option<(DateTime<Tz>, KeyboardBacklightState, DateTime<Tz>)> {
+    let tz = now.timezone();
+    let today = now.date_naive();
+    let mut transitions: Vec<_> = [today.pred_opt(), Some(today), today.succ_opt()]
+        .into_iter()
+        .flatten()
+        .flat_map(|date| {
+            entries
+                .iter()
+                .filter_map(|(time, level)| Some((local_datetime(&tz, date, *time)?, *level)))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    transitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let (previous, level) = transitions.iter().rev().find(|(time, _)| time <= now)?;
+    let (next, _) = transitions.iter().find(|(time, _)| time > now)?;
+    Some((previous.clone(), *level, next.clone()))
+}
+
+/// Task to apply the backlight schedule at each transition
+pub fn start_schedule_task(
+    config: &Config,
+    state_manager: KeyboardStateManager,
+    mut event_receiver: broadcast::Receiver<Event>,
+) {
+    let entries: Vec<_> = config
+        .backlight_schedule
+        .iter()
+        .map(|entry| (entry.at, entry.level))
+        .collect();
+    if entries.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut current_transition = None;
+        loop {
+            let now = Local::now();
+            let Some((previous, level, next)) = transitions_around(&entries, &now) else {
+                warn!("Failed to compute the backlight schedule transitions");
+                return;
+            };
+
+            if current_transition != Some(previous) {
+                info!(
+                    "Backlight schedule: {} since {}",
+                    level.name(),
+                    previous.format("%Y-%m-%d %H:%M")
+                );
+                state_manager.set_scheduled_keyboard_backlight(level);
+                current_transition = Some(previous);
+            }
+
+            let until_next = (next - now).to_std().unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(until_next.min(MAX_CHECK_INTERVAL)) => {}
+                result = event_receiver.recv() => {
+                    match result {
+                        // catch up on transitions missed while suspended
+                        Ok(Event::Suspended(false)) => {}
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeyboardBacklightState::{High, Low, Off};
+    use chrono::{FixedOffset, MappedLocalTime, NaiveDateTime, Timelike as _};
+
+    /// UTC+1 in winter and UTC+2 in summer, switching at 01:00 UTC like central Europe in 2026
+    #[derive(Clone, Copy, Debug)]
+    struct TestZone;
+
+    fn offset(hours: i32) -> FixedOffset {
+        FixedOffset::east_opt(hours * 3600).unwrap()
+    }
+
+    fn utc(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    impl TimeZone for TestZone {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            TestZone
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> MappedLocalTime<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(
+            &self,
+            local: &NaiveDateTime,
+        ) -> MappedLocalTime<FixedOffset> {
+            // the larger offset gives the earlier instant
+            let valid: Vec<_> = [offset(2), offset(1)]
+                .into_iter()
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - *offset)) == *offset)
+                .collect();
+            match valid[..] {
+                [] => MappedLocalTime::None,
+                [offset] => MappedLocalTime::Single(offset),
+                [earlier, later] => MappedLocalTime::Ambiguous(earlier, later),
+                _ => unreachable!(),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, time: &NaiveDateTime) -> FixedOffset {
+            if *time >= utc("2026-03-29 01:00") && *time < utc("2026-10-25 01:00") {
+                offset(2)
+            } else {
+                offset(1)
+            }
+        }
+    }
+
+    fn at(date: &str) -> DateTime<TestZone> {
+        TestZone.from_local_datetime(&utc(date)).earliest().unwrap()
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn skipped_time_happens_after_the_jump() {
+        // 02:00 to 03:00 is skipped on 2026-03-29
+        let datetime = local_datetime(&TestZone, utc("2026-03-29 00:00").date(), time("02:30"));
+        assert_eq!(datetime, Some(at("2026-03-29 03:00")));
+        assert_eq!(datetime.unwrap().naive_utc(), utc("2026-03-29 01:00"));
+    }
+
+    #[test]
+    fn repeated_time_uses_the_earlier_one() {
+        // 02:00 to 03:00 happens twice on 2026-10-25
+        let datetime = local_datetime(&TestZone, utc("2026-10-25 00:00").date(), time("02:30"));
+        assert_eq!(datetime.unwrap().naive_utc(), utc("2026-10-25 00:30"));
+    }
+
+    #[test]
+    fn transitions_on_spring_forward() {
+        let entries = [(time("02:30"), Off), (time("20:00"), High)];
+        let (previous, level, next) =
+            transitions_around(&entries, &at("2026-03-29 01:59")).unwrap();
+        assert_eq!(previous, at("2026-03-28 20:00"));
+        assert_eq!(level, High);
+        assert_eq!(next, at("2026-03-29 03:00"));
+
+        let (previous, level, _) = transitions_around(&entries, &at("2026-03-29 03:00")).unwrap();
+        assert_eq!(previous, at("2026-03-29 03:00"));
+        assert_eq!(level, Off);
+    }
+
+    #[test]
+    fn transitions_on_fall_back() {
+        let entries = [(time("02:30"), Off), (time("20:00"), High)];
+        let now = at("2026-10-25 02:45");
+        let (previous, level, next) = transitions_around(&entries, &now).unwrap();
+        assert_eq!(previous.naive_utc(), utc("2026-10-25 00:30"));
+        assert_eq!(level, Off);
+        assert_eq!(next, at("2026-10-25 20:00"));
+
+        // the second 02:45 doesn't repeat the transition
+        let now = now + TimeDelta::hours(1);
+        assert_eq!(now.hour(), 2);
+        let (previous, _, _) = transitions_around(&entries, &now).unwrap();
+        assert_eq!(previous.naive_utc(), utc("2026-10-25 00:30"));
+    }
+
+    #[test]
+    fn single_entry_repeats_daily() {
+        let entries = [(time("18:00"), Low)];
+        let (previous, level, next) =
+            transitions_around(&entries, &at("2026-06-01 12:00")).unwrap();
+        assert_eq!(previous, at("2026-05-31 18:00"));
+        assert_eq!(level, Low);
+        assert_eq!(next, at("2026-06-01 18:00"));
+
+        let (previous, _, next) = transitions_around(&entries, &at("2026-06-01 18:00")).unwrap();
+        assert_eq!(previous, at("2026-06-01 18:00"));
+        assert_eq!(next, at("2026-06-02 18:00"));
+    }
+
+    #[test]
+    fn entries_are_parsed_as_hours_and_minutes() {
+        let entry: BacklightScheduleEntry =
+            toml::from_str("at = \"18:30\"\nlevel = \"Low\"").unwrap();
+        assert_eq!(entry.at, time("18:30"));
+        assert_eq!(
+            toml::to_string(&entry).unwrap(),
+            "at = \"18:30\"\nlevel = \"Low\"\n"
+        );
+        assert!(
+            toml::from_str::<BacklightScheduleEntry>("at = \"25:00\"\nlevel = \"Low\"").is_err()
+        );
+    }
+}
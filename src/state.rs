@@ -1,8 +1,12 @@
 use crate::events::Event;
+use crate::history::{EventHistory, HistoryEntry, HistorySource};
+use crate::power_source::PowerSource;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 use tokio::sync::broadcast;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum KeyboardBacklightState {
     Off,
     Low,
@@ -19,20 +23,150 @@ impl KeyboardBacklightState {
             Self::High => Self::Off,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+}
+
+/// Stages of keyboard inactivity
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleState {
+    Active,
+    /// backlight is at most low
+    Dimmed,
+    /// backlight is disabled
+    Idle,
+}
+
+/// Preferred backlight level for each way the keyboard can be connected
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BacklightLevels {
+    pub usb: KeyboardBacklightState,
+    pub bluetooth: KeyboardBacklightState,
+}
+
+impl BacklightLevels {
+    fn get(&self, usb_attached: bool) -> KeyboardBacklightState {
+        if usb_attached {
+            self.usb
+        } else {
+            self.bluetooth
+        }
+    }
+
+    fn get_mut(&mut self, usb_attached: bool) -> &mut KeyboardBacklightState {
+        if usb_attached {
+            &mut self.usb
+        } else {
+            &mut self.bluetooth
+        }
+    }
 }
 
 /// Inner state structure containing all keyboard state
 struct InnerState {
-    backlight: KeyboardBacklightState,
+    /// the level of the current connection mode is used, USB while attached, Bluetooth otherwise
+    backlight: BacklightLevels,
+
+    /// level picked from the ambient light, used instead of the selected level unless paused
+    auto_backlight: Option<KeyboardBacklightState>,
+
+    /// set by a manual backlight change, cleared when idle or suspend ends
+    auto_backlight_paused: bool,
+
+    /// level set by the backlight schedule at its last transition, takes precedence over the auto backlight
+    scheduled_backlight: Option<KeyboardBacklightState>,
+
+    /// set by a manual backlight change, cleared at the next schedule transition
+    schedule_overridden: bool,
     mic_mute_led: bool,
 
     /// when suspended, both backlight and mic mute led are disabled
     is_suspended: bool,
 
-    /// when idle, only backlight is disabled
-    is_idle: bool,
+    /// when dimmed or idle, only backlight is affected
+    idle: IdleState,
     is_usb_attached: bool,
+    power_source: PowerSource,
+    /// event nodes of the keyboard connected over Bluetooth
+    bluetooth_keyboards: Vec<String>,
     is_secondary_display_enabled: bool,
+
+    /// secondary display state chosen manually while the keyboard is detached,
+    /// cleared when the keyboard is attached or detached
+    secondary_display_override: Option<bool>,
+
+    /// when enabled, Fn needs to be held to input F1-F12
+    fn_lock: bool,
+
+    /// platform profile selected through the daemon, re-applied after resume
+    platform_profile: Option<String>,
+
+    /// battery charge limit selected through the daemon, re-applied after resume
+    battery_charge_limit: Option<u8>,
+
+    /// state of the Toggle key functions by name, missing means off
+    toggles: HashMap<String, bool>,
+
+    /// bus name of the media player controlled last
+    last_media_player: Option<String>,
+}
+
+impl InnerState {
+    /// The backlight level before idle and suspend are applied
+    fn base_backlight(&self) -> KeyboardBacklightState {
+        if let Some(level) = self.scheduled_backlight
+            && !self.schedule_overridden
+        {
+            return level;
+        }
+        match self.auto_backlight {
+            Some(level) if !self.auto_backlight_paused => level,
+            _ => self.backlight.get(self.is_usb_attached),
+        }
+    }
+
+    /// The backlight level shown on the keyboard, taking idle and suspend into account
+    fn effective_backlight(&self) -> KeyboardBacklightState {
+        if self.is_suspended {
+            return KeyboardBacklightState::Off;
+        }
+        let base = self.base_backlight();
+        match self.idle {
+            IdleState::Active => base,
+            IdleState::Dimmed => base.min(KeyboardBacklightState::Low),
+            IdleState::Idle => KeyboardBacklightState::Off,
+        }
+    }
+}
+
+/// Daemon state reported by the `status` request of the control socket
+#[derive(Serialize)]
+pub struct Status {
+    /// the level shown on the keyboard, taking idle, suspend, auto backlight and the schedule into account
+    pub effective_backlight: KeyboardBacklightState,
+    /// the levels selected by the user for each connection mode
+    pub selected_backlight: BacklightLevels,
+    pub auto_backlight: Option<KeyboardBacklightState>,
+    pub scheduled_backlight: Option<KeyboardBacklightState>,
+    pub mic_mute_led: bool,
+    pub suspended: bool,
+    pub idle: IdleState,
+    pub usb_attached: bool,
+    pub bluetooth_keyboards: Vec<String>,
+    pub power_source: PowerSource,
+    pub secondary_display: bool,
+    pub fn_lock: bool,
+    pub platform_profile: Option<String>,
+    pub battery_charge_limit: Option<u8>,
+    pub toggles: BTreeMap<String, bool>,
 }
 
 /// Shared state manager that maintains keyboard state across attach/detach cycles
@@ -40,26 +174,56 @@ struct InnerState {
 pub struct KeyboardStateManager {
     state: Arc<RwLock<InnerState>>,
     sender: broadcast::Sender<Event>,
+    history: EventHistory,
 }
 
 impl KeyboardStateManager {
-    pub fn new(is_usb_attached: bool, sender: broadcast::Sender<Event>) -> Self {
+    pub fn new(
+        is_usb_attached: bool,
+        fn_lock: bool,
+        backlight: BacklightLevels,
+        sender: broadcast::Sender<Event>,
+    ) -> Self {
         Self {
             state: Arc::new(RwLock::new(InnerState {
-                backlight: KeyboardBacklightState::Low,
+                backlight,
+                auto_backlight: None,
+                auto_backlight_paused: false,
+                scheduled_backlight: None,
+                schedule_overridden: false,
                 mic_mute_led: false,
                 is_suspended: false,
-                is_idle: false,
+                idle: IdleState::Active,
                 is_usb_attached,
+                power_source: PowerSource::Ac,
+                bluetooth_keyboards: Vec::new(),
                 is_secondary_display_enabled: !is_usb_attached,
+                secondary_display_override: None,
+                fn_lock,
+                platform_profile: None,
+                battery_charge_limit: None,
+                toggles: HashMap::new(),
+                last_media_player: None,
             })),
             sender,
+            history: EventHistory::new(),
         }
     }
 
+    /// Add an entry to the event history
+    pub fn record(&self, source: HistorySource, message: impl Into<String>) {
+        self.history.record(source, message.into());
+    }
+
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.entries()
+    }
+
     pub fn suspend_start(&self) {
+        self.record(HistorySource::State, "Suspend started");
         let mut state = self.state.write().unwrap();
         state.is_suspended = true;
+        self.sender.send(Event::Suspended(true)).ok();
         self.sender.send(Event::MicMuteLed(false)).ok();
         self.sender
             .send(Event::Backlight(KeyboardBacklightState::Off))
@@ -67,32 +231,39 @@ impl KeyboardStateManager {
     }
 
     pub fn suspend_end(&self) {
+        self.record(HistorySource::State, "Suspend ended");
         let mut state = self.state.write().unwrap();
         state.is_suspended = false;
+        state.auto_backlight_paused = false;
         drop(state);
+        self.sender.send(Event::Suspended(false)).ok();
         self.sender
             .send(Event::MicMuteLed(self.get_mic_mute_led()))
             .ok();
         self.sender
             .send(Event::Backlight(self.get_keyboard_backlight()))
             .ok();
+        // the firmware may reset the platform profile and battery charge limit during suspend
+        if let Some(profile) = self.get_platform_profile() {
+            self.sender.send(Event::PlatformProfile(profile)).ok();
+        }
+        if let Some(limit) = self.get_battery_charge_limit() {
+            self.sender.send(Event::BatteryChargeLimit(limit)).ok();
+        }
     }
 
-    pub fn idle_start(&self) {
-        let mut state = self.state.write().unwrap();
-        state.is_idle = true;
-        self.sender
-            .send(Event::Backlight(KeyboardBacklightState::Off))
-            .ok();
-    }
-
-    pub fn idle_end(&self) {
+    pub fn set_idle_state(&self, idle: IdleState) {
+        self.record(HistorySource::State, format!("Idle state: {:?}", idle));
         let mut state = self.state.write().unwrap();
-        state.is_idle = false;
-        drop(state);
-        self.sender
-            .send(Event::Backlight(self.get_keyboard_backlight()))
-            .ok();
+        if idle == IdleState::Active && state.idle != IdleState::Active {
+            state.auto_backlight_paused = false;
+        }
+        state.idle = idle;
+        if !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
+        }
     }
 
     pub fn set_mic_mute_led(&self, enabled: bool) {
@@ -120,37 +291,105 @@ impl KeyboardStateManager {
         }
     }
 
+    /// Set the backlight level of the current connection mode,
+    /// this pauses the auto backlight and overrides the schedule until its next transition
     pub fn set_keyboard_backlight(&self, new_state: KeyboardBacklightState) {
         let mut state = self.state.write().unwrap();
-        state.backlight = new_state;
-        if !state.is_idle && !state.is_suspended {
+        let usb_attached = state.is_usb_attached;
+        *state.backlight.get_mut(usb_attached) = new_state;
+        state.auto_backlight_paused = state.auto_backlight.is_some();
+        state.schedule_overridden = state.scheduled_backlight.is_some();
+        if state.idle == IdleState::Active && !state.is_suspended {
             self.sender.send(Event::Backlight(new_state)).ok();
         }
     }
 
+    /// Cycle from the level currently shown, like `set_keyboard_backlight`
     pub fn toggle_keyboard_backlight(&self) {
         let mut state = self.state.write().unwrap();
-        state.backlight = state.backlight.next();
-        if !state.is_idle && !state.is_suspended {
-            self.sender.send(Event::Backlight(state.backlight)).ok();
+        let new_state = state.base_backlight().next();
+        let usb_attached = state.is_usb_attached;
+        *state.backlight.get_mut(usb_attached) = new_state;
+        state.auto_backlight_paused = state.auto_backlight.is_some();
+        state.schedule_overridden = state.scheduled_backlight.is_some();
+        if state.idle == IdleState::Active && !state.is_suspended {
+            self.sender.send(Event::Backlight(new_state)).ok();
+        }
+    }
+
+    /// Set the level picked from the ambient light, ignored while paused by a manual change
+    pub fn set_auto_keyboard_backlight(&self, level: KeyboardBacklightState) {
+        let mut state = self.state.write().unwrap();
+        state.auto_backlight = Some(level);
+        // an active schedule takes precedence over the auto backlight
+        if !state.auto_backlight_paused && state.idle == IdleState::Active && !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
+        }
+    }
+
+    /// Set the level of a schedule transition, this ends a manual override
+    pub fn set_scheduled_keyboard_backlight(&self, level: KeyboardBacklightState) {
+        self.record(
+            HistorySource::State,
+            format!("Scheduled backlight: {}", level.name()),
+        );
+        let mut state = self.state.write().unwrap();
+        state.scheduled_backlight = Some(level);
+        state.schedule_overridden = false;
+        if state.idle == IdleState::Active && !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
         }
     }
 
     pub fn get_keyboard_backlight(&self) -> KeyboardBacklightState {
         let state = self.state.read().unwrap();
-        if state.is_suspended || state.is_idle {
-            KeyboardBacklightState::Off
-        } else {
-            state.backlight
+        state.effective_backlight()
+    }
+
+    pub fn set_backlight_levels(&self, levels: BacklightLevels) {
+        let mut state = self.state.write().unwrap();
+        state.backlight = levels;
+        if state.idle == IdleState::Active && !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
         }
     }
 
+    pub fn get_backlight_levels(&self) -> BacklightLevels {
+        let state = self.state.read().unwrap();
+        state.backlight
+    }
+
+    pub fn set_fn_lock(&self, enabled: bool) {
+        let mut state = self.state.write().unwrap();
+        state.fn_lock = enabled;
+        self.sender.send(Event::FnLock(enabled)).ok();
+    }
+
+    pub fn toggle_fn_lock(&self) {
+        let mut state = self.state.write().unwrap();
+        state.fn_lock = !state.fn_lock;
+        self.sender.send(Event::FnLock(state.fn_lock)).ok();
+    }
+
+    pub fn get_fn_lock(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state.fn_lock
+    }
+
     pub fn set_secondary_display(&self, enabled: bool) {
         let mut state = self.state.write().unwrap();
         state.is_secondary_display_enabled = enabled;
 
         if state.is_usb_attached {
             state.is_secondary_display_enabled = false;
+        } else {
+            state.secondary_display_override = Some(enabled);
         }
 
         self.sender
@@ -164,6 +403,8 @@ impl KeyboardStateManager {
 
         if state.is_usb_attached {
             state.is_secondary_display_enabled = false;
+        } else {
+            state.secondary_display_override = Some(state.is_secondary_display_enabled);
         }
 
         self.sender
@@ -172,22 +413,150 @@ impl KeyboardStateManager {
     }
 
     pub fn set_usb_keyboard_attached(&self, attached: bool) {
+        self.record(
+            HistorySource::Usb,
+            if attached { "Attached" } else { "Detached" },
+        );
         let mut state = self.state.write().unwrap();
         state.is_usb_attached = attached;
+        state.secondary_display_override = None;
 
-        if attached {
-            state.is_secondary_display_enabled = false;
-        } else {
-            state.is_secondary_display_enabled = true;
-        }
+        state.is_secondary_display_enabled = !attached;
 
         self.sender
             .send(Event::SecondaryDisplay(state.is_secondary_display_enabled))
             .ok();
+        self.sender.send(Event::UsbKeyboardAttached(attached)).ok();
+        // switch to the backlight level of the new connection mode
+        if !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
+        }
     }
 
     pub fn is_secondary_display_enabled(&self) -> bool {
         let state = self.state.read().unwrap();
         state.is_secondary_display_enabled
     }
+
+    pub fn add_bluetooth_keyboard(&self, path: String) {
+        self.record(HistorySource::Bluetooth, format!("Connected on {}", path));
+        let mut state = self.state.write().unwrap();
+        if !state.bluetooth_keyboards.contains(&path) {
+            state.bluetooth_keyboards.push(path);
+        }
+        // apply the backlight level of the current connection mode to the newly connected keyboard
+        if !state.is_suspended {
+            self.sender
+                .send(Event::Backlight(state.effective_backlight()))
+                .ok();
+        }
+    }
+
+    pub fn remove_bluetooth_keyboard(&self, path: &str) {
+        self.record(
+            HistorySource::Bluetooth,
+            format!("Disconnected from {}", path),
+        );
+        let mut state = self.state.write().unwrap();
+        state
+            .bluetooth_keyboards
+            .retain(|keyboard| keyboard != path);
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.read().unwrap();
+        Status {
+            effective_backlight: state.effective_backlight(),
+            selected_backlight: state.backlight,
+            auto_backlight: state.auto_backlight,
+            scheduled_backlight: state.scheduled_backlight,
+            mic_mute_led: state.mic_mute_led,
+            suspended: state.is_suspended,
+            idle: state.idle,
+            usb_attached: state.is_usb_attached,
+            bluetooth_keyboards: state.bluetooth_keyboards.clone(),
+            power_source: state.power_source,
+            secondary_display: state.is_secondary_display_enabled,
+            fn_lock: state.fn_lock,
+            platform_profile: state.platform_profile.clone(),
+            battery_charge_limit: state.battery_charge_limit,
+            toggles: state.toggles.clone().into_iter().collect(),
+        }
+    }
+
+    pub fn get_secondary_display_override(&self) -> Option<bool> {
+        let state = self.state.read().unwrap();
+        state.secondary_display_override
+    }
+
+    pub fn is_usb_keyboard_attached(&self) -> bool {
+        let state = self.state.read().unwrap();
+        state.is_usb_attached
+    }
+
+    /// Only sends an event if the power source changed
+    pub fn set_power_source(&self, power_source: PowerSource) {
+        let mut state = self.state.write().unwrap();
+        if state.power_source != power_source {
+            state.power_source = power_source;
+            self.sender.send(Event::PowerSource(power_source)).ok();
+        }
+    }
+
+    pub fn get_power_source(&self) -> PowerSource {
+        let state = self.state.read().unwrap();
+        state.power_source
+    }
+
+    pub fn set_platform_profile(&self, profile: String) {
+        let mut state = self.state.write().unwrap();
+        state.platform_profile = Some(profile.clone());
+        self.sender.send(Event::PlatformProfile(profile)).ok();
+    }
+
+    pub fn get_platform_profile(&self) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.platform_profile.clone()
+    }
+
+    pub fn set_battery_charge_limit(&self, limit: u8) {
+        let mut state = self.state.write().unwrap();
+        state.battery_charge_limit = Some(limit);
+        self.sender.send(Event::BatteryChargeLimit(limit)).ok();
+    }
+
+    pub fn get_battery_charge_limit(&self) -> Option<u8> {
+        let state = self.state.read().unwrap();
+        state.battery_charge_limit
+    }
+
+    /// Flip the toggle named `name`, returns the new state
+    pub fn flip_toggle(&self, name: &str) -> bool {
+        let mut state = self.state.write().unwrap();
+        let toggle = state.toggles.entry(name.to_string()).or_insert(false);
+        *toggle = !*toggle;
+        *toggle
+    }
+
+    pub fn set_toggle(&self, name: &str, enabled: bool) {
+        let mut state = self.state.write().unwrap();
+        state.toggles.insert(name.to_string(), enabled);
+    }
+
+    pub fn get_toggle(&self, name: &str) -> bool {
+        let state = self.state.read().unwrap();
+        state.toggles.get(name).copied().unwrap_or(false)
+    }
+
+    pub fn set_last_media_player(&self, player: String) {
+        let mut state = self.state.write().unwrap();
+        state.last_media_player = Some(player);
+    }
+
+    pub fn get_last_media_player(&self) -> Option<String> {
+        let state = self.state.read().unwrap();
+        state.last_media_player.clone()
+    }
 }
@@ -0,0 +1,21 @@
+use crate::persisted_state::update_persisted_state;
+use crate::state::KeyboardStateManager;
+
+/// Persist the state of the toggle named `name`, or forget it if `state` is None
+pub async fn persist_toggle(name: &str, state: Option<bool>) {
+    update_persisted_state(|persisted| match state {
+        Some(state) => {
+            persisted.toggles.insert(name.to_string(), state);
+        }
+        None => {
+            persisted.toggles.remove(name);
+        }
+    })
+    .await;
+}
+
+/// Reset the toggle named `name` to off without running its `off` action
+pub async fn reset_toggle(state_manager: &KeyboardStateManager, name: &str) {
+    state_manager.set_toggle(name, false);
+    persist_toggle(name, None).await;
+}
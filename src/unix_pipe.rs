@@ -8,8 +8,11 @@ use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
 
 use crate::config::Config;
+use crate::history::HistorySource;
 use crate::idle_detection::ActivityNotifier;
+use crate::mute_state::AudioController;
 use crate::state::{KeyboardBacklightState, KeyboardStateManager};
+use crate::toggle::reset_toggle;
 
 pub struct UnixPipe {
     reader: BufReader<File>,
@@ -64,10 +67,14 @@ impl UnixPipe {
     }
 }
 
+/// Commands that take an argument after a space, all other commands take none
+const COMMANDS_WITH_ARGUMENT: &[&str] = &["audio_output_set", "toggle_reset"];
+
 pub fn start_receive_commands_task(
     config: &Config,
     state_manager: KeyboardStateManager,
     activity_notifier: ActivityNotifier,
+    audio_controller: AudioController,
 ) {
     let path = PathBuf::from(&config.pipe_path);
     tokio::spawn(async move {
@@ -75,7 +82,16 @@ pub fn start_receive_commands_task(
         loop {
             if let Some(line) = pipe.receive_next_command().await {
                 info!("Received command: {}", line);
-                match line.as_str() {
+                state_manager.record(HistorySource::Pipe, line.clone());
+                let (command, argument) = match line.split_once(' ') {
+                    Some((command, argument)) => (command, argument.trim()),
+                    None => (line.as_str(), ""),
+                };
+                if !argument.is_empty() && !COMMANDS_WITH_ARGUMENT.contains(&command) {
+                    warn!("Pipe command {} takes no argument: {}", command, line);
+                    continue;
+                }
+                match command {
                     "suspend_start" => {
                         state_manager.suspend_start();
                     }
@@ -107,6 +123,15 @@ pub fn start_receive_commands_task(
                     "backlight_high" => {
                         state_manager.set_keyboard_backlight(KeyboardBacklightState::High);
                     }
+                    "fn_lock_toggle" => {
+                        state_manager.toggle_fn_lock();
+                    }
+                    "fn_lock_on" => {
+                        state_manager.set_fn_lock(true);
+                    }
+                    "fn_lock_off" => {
+                        state_manager.set_fn_lock(false);
+                    }
                     "secondary_display_toggle" => {
                         state_manager.toggle_secondary_display();
                     }
@@ -116,6 +141,17 @@ pub fn start_receive_commands_task(
                     "secondary_display_off" => {
                         state_manager.set_secondary_display(false);
                     }
+                    "audio_output_next" => {
+                        audio_controller.cycle_default_sink(Vec::new(), true).await;
+                    }
+                    "audio_output_set" => {
+                        audio_controller
+                            .set_default_sink(argument.to_string(), true)
+                            .await;
+                    }
+                    "toggle_reset" => {
+                        reset_toggle(&state_manager, argument).await;
+                    }
                     _ => {
                         warn!("Unknown pipe command: {}", line);
                     }
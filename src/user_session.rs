@@ -0,0 +1,114 @@
+use std::{io, os::unix::net::UnixStream, path::PathBuf, thread};
+
+use log::warn;
+use nix::libc;
+use tokio::sync::oneshot;
+use users::get_user_by_uid;
+use zbus::{Connection, Proxy, zvariant::OwnedObjectPath};
+
+const LOGIN1_DESTINATION: &str = "org.freedesktop.login1";
+
+/// Ask logind for the user of the active session, sessions on seat0 are preferred
+async fn active_session_uid() -> zbus::Result<Option<u32>> {
+    let connection = Connection::system().await?;
+    let reply = connection
+        .call_method(
+            Some(LOGIN1_DESTINATION),
+            "/org/freedesktop/login1",
+            Some("org.freedesktop.login1.Manager"),
+            "ListSessions",
+            &(),
+        )
+        .await?;
+    // session id, uid, user name, seat id, session object path
+    let mut sessions: Vec<(String, u32, String, String, OwnedObjectPath)> =
+        reply.body().deserialize()?;
+    sessions.sort_by_key(|(_, _, _, seat, _)| seat != "seat0");
+
+    for (_, uid, _, _, path) in sessions {
+        let session = Proxy::new(
+            &connection,
+            LOGIN1_DESTINATION,
+            path,
+            "org.freedesktop.login1.Session",
+        )
+        .await?;
+        if session.get_property::<bool>("Active").await? {
+            return Ok(Some(uid));
+        }
+    }
+    Ok(None)
+}
+
+/// Find a file in the runtime directory (`/run/user/<uid>`) of the user of the active session.
+/// Returns the uid of the user and the path of the file.
+pub async fn find_user_runtime_file(name: &str) -> Option<(u32, PathBuf)> {
+    let uid = match active_session_uid().await {
+        Ok(Some(uid)) => uid,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!("Failed to query the active session from logind: {}", e);
+            return None;
+        }
+    };
+    let path = PathBuf::from(format!("/run/user/{}", uid)).join(name);
+    path.exists().then_some((uid, path))
+}
+
+/// Connect to the unix socket at `path` with the credentials of `uid`.
+/// The credentials are only changed for a short-lived thread, raw syscalls are used since
+/// the libc wrappers change the credentials of all threads of the process.
+async fn connect_as_user(path: PathBuf, uid: u32, gid: u32) -> io::Result<UnixStream> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let result = (|| {
+            // SAFETY: the syscalls only change the credentials of the current thread, which exits afterwards
+            unsafe {
+                if libc::syscall(libc::SYS_setgroups, 0, std::ptr::null::<libc::gid_t>()) != 0
+                    || libc::syscall(libc::SYS_setresgid, gid, gid, gid) != 0
+                    || libc::syscall(libc::SYS_setresuid, uid, uid, uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            UnixStream::connect(&path)
+        })();
+        tx.send(result).ok();
+    });
+    rx.await
+        .unwrap_or_else(|_| Err(io::Error::other("connect thread exited")))
+}
+
+/// Connect to the session bus of the user of the active session.
+/// dbus-daemon only lets the owner of a session bus authenticate, so the socket is connected
+/// with the user's credentials and the user's uid is sent for EXTERNAL authentication.
+pub async fn connect_session_bus() -> Option<Connection> {
+    let Some((uid, bus_path)) = find_user_runtime_file("bus").await else {
+        warn!("Failed to find the session bus of the active user");
+        return None;
+    };
+    let Some(user) = get_user_by_uid(uid) else {
+        warn!("Failed to find the user with uid {}", uid);
+        return None;
+    };
+
+    let connection = async {
+        let stream = connect_as_user(bus_path, uid, user.primary_group_id()).await?;
+        stream.set_nonblocking(true)?;
+        zbus::connection::Builder::unix_stream(tokio::net::UnixStream::from_std(stream)?)
+            .user_id(uid)
+            .build()
+            .await
+    }
+    .await;
+    match connection {
+        Ok(connection) => Some(connection),
+        Err(e) => {
+            warn!(
+                "Failed to connect to the session bus of user {}: {}",
+                uid, e
+            );
+            None
+        }
+    }
+}
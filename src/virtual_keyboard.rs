@@ -1,11 +1,80 @@
 use evdev_rs::{
-    DeviceWrapper, InputEvent, UInputDevice, UninitDevice,
-    enums::{BusType, EV_KEY, EV_SYN, EventCode},
+    AbsInfo, DeviceWrapper, EnableCodeData, InputEvent, UInputDevice, UninitDevice,
+    enums::{BusType, EV_ABS, EV_KEY, EV_REL, EV_SYN, EventCode, int_to_ev_key},
+};
+use log::warn;
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
 };
-use std::time::SystemTime;
 
 use crate::config::{Config, KeyFunction};
 
+/// Maximum value of the absolute pointer axes, covering the whole screen layout
+const ABS_MAX: i32 = 65535;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    fn key(&self) -> EV_KEY {
+        match self {
+            Self::Left => EV_KEY::BTN_LEFT,
+            Self::Right => EV_KEY::BTN_RIGHT,
+            Self::Middle => EV_KEY::BTN_MIDDLE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ScrollDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Panel {
+    /// The top display
+    Primary,
+    /// The bottom display, only reachable when it is enabled
+    Secondary,
+}
+
+/// The transport a key press came from, each has its own set of virtual devices so that
+/// held keys of one don't interfere with the other
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyboardSource {
+    Usb,
+    Bluetooth,
+}
+
+impl KeyboardSource {
+    fn device_name(&self, suffix: &str) -> String {
+        let source = match self {
+            Self::Usb => "USB",
+            Self::Bluetooth => "Bluetooth",
+        };
+        format!("Zenbook Duo Daemon ({}){}", source, suffix)
+    }
+
+    /// Set as the version of the input id, so udev and hwdb rules can tell the sources apart
+    fn version(&self) -> u16 {
+        match self {
+            Self::Usb => 1,
+            Self::Bluetooth => 2,
+        }
+    }
+}
+
 pub enum KeyEventType {
     Release,
     Press,
@@ -20,74 +89,409 @@ impl KeyEventType {
     }
 }
 
+/// Errors of the virtual input devices
+#[derive(Debug)]
+pub enum VirtualDeviceError {
+    /// Creating a uinput device failed
+    Create(io::Error),
+    /// Writing events failed, even after recreating the device if it was gone
+    Write(io::Error),
+}
+
+impl fmt::Display for VirtualDeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create(e) => write!(f, "Failed to create uinput device: {}", e),
+            Self::Write(e) => write!(f, "Failed to write to uinput device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VirtualDeviceError {}
+
+#[derive(Clone, Copy, Debug)]
+enum DeviceKind {
+    Keyboard,
+    Pointer,
+    AbsolutePointer,
+}
+
+/// The virtual devices of one keyboard source.
+/// Clone this to share across multiple components.
+#[derive(Clone)]
 pub struct VirtualKeyboard {
+    devices: Arc<Mutex<VirtualDevices>>,
+}
+
+struct VirtualDevices {
     device: UInputDevice,
+    /// Keys the keyboard device was created with, see `all_keys`
+    enabled_keys: Vec<EV_KEY>,
     pressed_keys: Vec<EV_KEY>,
+    source: KeyboardSource,
+    vendor_id: u16,
+    product_id: u16,
+    /// Companion device for relative motion, scrolling and mouse buttons
+    pointer: UInputDevice,
+    /// Companion device for absolute motion, compositors map it to the whole screen layout
+    absolute_pointer: UInputDevice,
 }
 
-impl VirtualKeyboard {
-    pub fn new(config: &Config) -> Self {
-        let u = UninitDevice::new().unwrap();
-
-        u.set_name("Zenbook Duo Daemon");
-        u.set_bustype(BusType::BUS_VIRTUAL as u16);
-        u.set_vendor_id(config.vendor_id());
-        u.set_product_id(config.product_id());
-
-        let enable_key = |key_function: &KeyFunction| {
-            if let KeyFunction::KeyBind(keys) = key_function {
-                for key in keys {
-                    u.enable(EventCode::EV_KEY(*key)).unwrap();
-                }
-            }
-        };
-        enable_key(&config.keyboard_backlight_key);
-        enable_key(&config.brightness_down_key);
-        enable_key(&config.brightness_up_key);
-        enable_key(&config.swap_up_down_display_key);
-        enable_key(&config.microphone_mute_key);
-        enable_key(&config.emoji_picker_key);
-        enable_key(&config.myasus_key);
-        enable_key(&config.toggle_secondary_display_key);
-
-        Self {
-            device: UInputDevice::create_from_device(&u).unwrap(),
-            pressed_keys: Vec::new(),
-        }
+fn write_events(device: &UInputDevice, events: &[(EventCode, i32)]) -> io::Result<()> {
+    let time = SystemTime::now().try_into().map_err(io::Error::other)?;
+    for (code, value) in events {
+        device.write_event(&InputEvent::new(&time, code, *value))?;
     }
+    let sync_event = InputEvent::new(&time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+    device.write_event(&sync_event)
+}
 
-    pub fn release_prev_and_press_keys(&mut self, keys: &[EV_KEY]) {
-        self.release_all_keys();
+/// The uinput device was destroyed or broke, e.g. after uinput was reloaded, recreating it fixes this
+fn is_device_gone(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::ENODEV) | Some(libc::EIO))
+}
+
+fn key_events(keys: &[EV_KEY], event_type: KeyEventType) -> Vec<(EventCode, i32)> {
+    keys.iter()
+        .map(|key| (EventCode::EV_KEY(*key), event_type.value()))
+        .collect()
+}
+
+fn new_uninit_device(
+    name: &str,
+    source: KeyboardSource,
+    vendor_id: u16,
+    product_id: u16,
+) -> Result<UninitDevice, VirtualDeviceError> {
+    let u = UninitDevice::new().ok_or_else(|| {
+        VirtualDeviceError::Create(io::Error::other("failed to allocate libevdev device"))
+    })?;
+
+    u.set_name(name);
+    u.set_bustype(BusType::BUS_VIRTUAL as u16);
+    u.set_vendor_id(vendor_id);
+    u.set_product_id(product_id);
+    u.set_version(source.version());
+    Ok(u)
+}
+
+/// Every `KEY_*` code, uinput devices can't gain capabilities after creation so all keys are
+/// enabled upfront. This lets bindings changed at runtime and keys passed through by the layer work.
+/// `BTN_*` codes are left out, udev would classify the device as a joystick or mouse.
+fn all_keys() -> impl Iterator<Item = EV_KEY> {
+    (1..EV_KEY::KEY_MAX as u32)
+        .filter_map(int_to_ev_key)
+        .filter(|key| format!("{:?}", key).starts_with("KEY_"))
+}
+
+impl VirtualDevices {
+    fn create_keyboard(
+        source: KeyboardSource,
+        vendor_id: u16,
+        product_id: u16,
+        keys: &[EV_KEY],
+    ) -> Result<UInputDevice, VirtualDeviceError> {
+        let u = new_uninit_device(&source.device_name(""), source, vendor_id, product_id)?;
 
-        let time = SystemTime::now().try_into().unwrap();
         for key in keys {
-            let event =
-                InputEvent::new(&time, &EventCode::EV_KEY(*key), KeyEventType::Press.value());
-            self.device.write_event(&event).unwrap();
+            u.enable(EventCode::EV_KEY(*key))
+                .map_err(VirtualDeviceError::Create)?;
         }
 
-        let sync_event = InputEvent::new(&time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
-        self.device.write_event(&sync_event).unwrap();
+        UInputDevice::create_from_device(&u).map_err(VirtualDeviceError::Create)
+    }
+
+    fn create_pointer(
+        source: KeyboardSource,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> Result<UInputDevice, VirtualDeviceError> {
+        let u = new_uninit_device(
+            &source.device_name(" Pointer"),
+            source,
+            vendor_id,
+            product_id,
+        )?;
+
+        for button in [MouseButton::Left, MouseButton::Right, MouseButton::Middle] {
+            u.enable(EventCode::EV_KEY(button.key()))
+                .map_err(VirtualDeviceError::Create)?;
+        }
+        for axis in [
+            EV_REL::REL_X,
+            EV_REL::REL_Y,
+            EV_REL::REL_WHEEL,
+            EV_REL::REL_HWHEEL,
+        ] {
+            u.enable(EventCode::EV_REL(axis))
+                .map_err(VirtualDeviceError::Create)?;
+        }
+
+        UInputDevice::create_from_device(&u).map_err(VirtualDeviceError::Create)
+    }
 
+    fn create_absolute_pointer(
+        source: KeyboardSource,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> Result<UInputDevice, VirtualDeviceError> {
+        let u = new_uninit_device(
+            &source.device_name(" Absolute Pointer"),
+            source,
+            vendor_id,
+            product_id,
+        )?;
+
+        // a button is required for the device to be recognized as a mouse
+        u.enable(EventCode::EV_KEY(EV_KEY::BTN_LEFT))
+            .map_err(VirtualDeviceError::Create)?;
+        for axis in [EV_ABS::ABS_X, EV_ABS::ABS_Y] {
+            let abs_info = AbsInfo {
+                value: 0,
+                minimum: 0,
+                maximum: ABS_MAX,
+                fuzz: 0,
+                flat: 0,
+                resolution: 0,
+            };
+            u.enable_event_code(
+                &EventCode::EV_ABS(axis),
+                Some(EnableCodeData::AbsInfo(abs_info)),
+            )
+            .map_err(VirtualDeviceError::Create)?;
+        }
+
+        UInputDevice::create_from_device(&u).map_err(VirtualDeviceError::Create)
+    }
+
+    fn device(&self, kind: DeviceKind) -> &UInputDevice {
+        match kind {
+            DeviceKind::Keyboard => &self.device,
+            DeviceKind::Pointer => &self.pointer,
+            DeviceKind::AbsolutePointer => &self.absolute_pointer,
+        }
+    }
+
+    /// The pressed keys are only updated once the events were written
+    fn press_keys(&mut self, keys: &[EV_KEY]) -> io::Result<()> {
+        write_events(&self.device, &key_events(keys, KeyEventType::Press))?;
         self.pressed_keys.extend(keys);
+        Ok(())
     }
 
-    pub fn release_all_keys(&mut self) {
+    fn release_keys(&mut self, keys: &[EV_KEY]) -> io::Result<()> {
+        let pressed_keys: Vec<_> = keys
+            .iter()
+            .filter(|key| self.pressed_keys.contains(key))
+            .copied()
+            .collect();
+        if !pressed_keys.is_empty() {
+            write_events(
+                &self.device,
+                &key_events(&pressed_keys, KeyEventType::Release),
+            )?;
+            self.pressed_keys.retain(|key| !keys.contains(key));
+        }
+        Ok(())
+    }
+
+    fn release_all_keys(&mut self) -> io::Result<()> {
         if !self.pressed_keys.is_empty() {
-            let time = SystemTime::now().try_into().unwrap();
-            for key in &self.pressed_keys {
-                let event = InputEvent::new(
-                    &time,
-                    &EventCode::EV_KEY(*key),
-                    KeyEventType::Release.value(),
-                );
-                self.device.write_event(&event).unwrap();
+            write_events(
+                &self.device,
+                &key_events(&self.pressed_keys, KeyEventType::Release),
+            )?;
+            self.pressed_keys.clear();
+        }
+        Ok(())
+    }
+
+    /// Replace the device of `kind` with a new one
+    fn recreate(&mut self, kind: DeviceKind) -> Result<(), VirtualDeviceError> {
+        let (source, vendor_id, product_id) = (self.source, self.vendor_id, self.product_id);
+        match kind {
+            DeviceKind::Keyboard => {
+                self.device =
+                    Self::create_keyboard(source, vendor_id, product_id, &self.enabled_keys)?
             }
+            DeviceKind::Pointer => {
+                self.pointer = Self::create_pointer(source, vendor_id, product_id)?
+            }
+            DeviceKind::AbsolutePointer => {
+                self.absolute_pointer =
+                    Self::create_absolute_pointer(source, vendor_id, product_id)?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtualKeyboard {
+    pub fn new(config: &Config, source: KeyboardSource) -> Result<Self, VirtualDeviceError> {
+        let mut enabled_keys: Vec<_> = all_keys().collect();
+        let mut enable_key = |key: EV_KEY| {
+            if !enabled_keys.contains(&key) {
+                enabled_keys.push(key);
+            }
+        };
+        config
+            .key_functions()
+            .flat_map(KeyFunction::bound_keys)
+            .for_each(&mut enable_key);
+        for binding in &config.layer_bindings {
+            binding.keys.iter().copied().for_each(&mut enable_key);
+        }
 
-            let sync_event = InputEvent::new(&time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
-            self.device.write_event(&sync_event).unwrap();
+        let (vendor_id, product_id) = (config.vendor_id(), config.product_id());
+        let devices = VirtualDevices {
+            device: VirtualDevices::create_keyboard(source, vendor_id, product_id, &enabled_keys)?,
+            enabled_keys,
+            pressed_keys: Vec::new(),
+            source,
+            vendor_id,
+            product_id,
+            pointer: VirtualDevices::create_pointer(source, vendor_id, product_id)?,
+            absolute_pointer: VirtualDevices::create_absolute_pointer(
+                source, vendor_id, product_id,
+            )?,
+        };
+        Ok(Self {
+            devices: Arc::new(Mutex::new(devices)),
+        })
+    }
 
-            self.pressed_keys.clear();
+    fn lock(&self) -> MutexGuard<'_, VirtualDevices> {
+        self.devices.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `write` on the devices, it writes to the device of `kind` and updates the pressed keys on success.
+    /// If the device is gone, it is recreated and `write` runs again.
+    fn write(
+        &self,
+        kind: DeviceKind,
+        write: impl Fn(&mut VirtualDevices) -> io::Result<()>,
+    ) -> Result<(), VirtualDeviceError> {
+        {
+            let mut devices = self.lock();
+            match write(&mut devices) {
+                Ok(()) => return Ok(()),
+                Err(e) if is_device_gone(&e) => {
+                    warn!("Virtual {:?} is gone, recreating it: {}", kind, e);
+                }
+                Err(e) => return Err(VirtualDeviceError::Write(e)),
+            }
+            devices.recreate(kind)?;
         }
+        // give udev and the compositor a moment to pick up the new device, events sent before are lost.
+        // The lock is released meanwhile so the other users of the devices aren't held up.
+        std::thread::sleep(Duration::from_millis(100));
+
+        let mut devices = self.lock();
+        // keys held on the old keyboard device are pressed again on the new one
+        if let DeviceKind::Keyboard = kind
+            && !devices.pressed_keys.is_empty()
+        {
+            write_events(
+                &devices.device,
+                &key_events(&devices.pressed_keys, KeyEventType::Press),
+            )
+            .map_err(VirtualDeviceError::Write)?;
+        }
+        write(&mut devices).map_err(VirtualDeviceError::Write)
+    }
+
+    /// Write `events` to the device of `kind`, see `write`
+    fn write_events(
+        &self,
+        kind: DeviceKind,
+        events: &[(EventCode, i32)],
+    ) -> Result<(), VirtualDeviceError> {
+        self.write(kind, |devices| write_events(devices.device(kind), events))
+    }
+
+    pub fn click(&self, button: MouseButton) -> Result<(), VirtualDeviceError> {
+        let code = EventCode::EV_KEY(button.key());
+        self.write_events(DeviceKind::Pointer, &[(code, KeyEventType::Press.value())])?;
+        self.write_events(
+            DeviceKind::Pointer,
+            &[(code, KeyEventType::Release.value())],
+        )
+    }
+
+    /// Scroll by `amount` wheel detents
+    pub fn scroll(
+        &self,
+        direction: ScrollDirection,
+        amount: i32,
+    ) -> Result<(), VirtualDeviceError> {
+        let event = match direction {
+            ScrollDirection::Up => (EventCode::EV_REL(EV_REL::REL_WHEEL), amount),
+            ScrollDirection::Down => (EventCode::EV_REL(EV_REL::REL_WHEEL), -amount),
+            ScrollDirection::Left => (EventCode::EV_REL(EV_REL::REL_HWHEEL), -amount),
+            ScrollDirection::Right => (EventCode::EV_REL(EV_REL::REL_HWHEEL), amount),
+        };
+        self.write_events(DeviceKind::Pointer, &[event])
+    }
+
+    /// Move the pointer to a position on `panel`, given in percent of the panel size.
+    /// Assumes the default layout with the secondary display directly below the primary display.
+    pub fn move_pointer_to(
+        &self,
+        panel: Panel,
+        x_percent: f64,
+        y_percent: f64,
+        is_secondary_display_enabled: bool,
+    ) -> Result<(), VirtualDeviceError> {
+        let (y_offset, y_scale) = match (panel, is_secondary_display_enabled) {
+            (Panel::Primary, false) => (0.0, 1.0),
+            (Panel::Primary, true) => (0.0, 0.5),
+            (Panel::Secondary, true) => (0.5, 0.5),
+            (Panel::Secondary, false) => {
+                warn!("Cannot move pointer to the secondary display, it is disabled");
+                return Ok(());
+            }
+        };
+
+        let to_abs = |fraction: f64| (fraction.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+        let x = to_abs(x_percent / 100.0);
+        let y = to_abs(y_offset + y_scale * y_percent / 100.0);
+        self.write_events(
+            DeviceKind::AbsolutePointer,
+            &[
+                (EventCode::EV_ABS(EV_ABS::ABS_X), x),
+                (EventCode::EV_ABS(EV_ABS::ABS_Y), y),
+            ],
+        )
+    }
+
+    pub fn release_prev_and_press_keys(&self, keys: &[EV_KEY]) -> Result<(), VirtualDeviceError> {
+        self.write(DeviceKind::Keyboard, |devices| {
+            devices.release_all_keys()?;
+            devices.press_keys(keys)
+        })
+    }
+
+    /// Press `keys` while keeping previously pressed keys down
+    pub fn press_keys(&self, keys: &[EV_KEY]) -> Result<(), VirtualDeviceError> {
+        self.write(DeviceKind::Keyboard, |devices| devices.press_keys(keys))
+    }
+
+    /// Release `keys` if they were pressed through this virtual keyboard
+    pub fn release_keys(&self, keys: &[EV_KEY]) -> Result<(), VirtualDeviceError> {
+        self.write(DeviceKind::Keyboard, |devices| devices.release_keys(keys))
+    }
+
+    /// Release `key` even if it wasn't pressed through this virtual keyboard,
+    /// e.g. a key that was held on a keyboard before the layer grabbed it
+    pub fn forward_release(&self, key: EV_KEY) -> Result<(), VirtualDeviceError> {
+        self.write(DeviceKind::Keyboard, |devices| {
+            write_events(&devices.device, &key_events(&[key], KeyEventType::Release))?;
+            devices.pressed_keys.retain(|pressed| *pressed != key);
+            Ok(())
+        })
+    }
+
+    pub fn release_all_keys(&self) -> Result<(), VirtualDeviceError> {
+        self.write(DeviceKind::Keyboard, VirtualDevices::release_all_keys)
     }
 }